@@ -0,0 +1,369 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use hyper::HeaderMap;
+
+use crate::geo::chain::ChainResolver;
+use crate::geo::record::{City, Continent, Country, Location, Names, Postal, Subdivision, Traits};
+use crate::geo::{GeoError, GeoLookup, GeoRecord, GeoResolver, ServiceTier};
+use crate::proxy::client_ip::CdnPreset;
+use crate::proxy::headers::HeaderMapping;
+
+/// Geo headers added by the CDN edges, names being in English
+struct CdnHeaders {
+    country: &'static str,
+    country_name: Option<&'static str>,
+    continent: Option<&'static str>,
+    region_code: &'static str,
+    region_name: &'static str,
+    city: &'static str,
+    postal_code: &'static str,
+    latitude: &'static str,
+    longitude: &'static str,
+    metro_code: &'static str,
+    time_zone: &'static str,
+    asn: Option<&'static str>,
+}
+
+const CLOUDFLARE: CdnHeaders = CdnHeaders {
+    country: "CF-IPCountry",
+    country_name: None,
+    continent: Some("CF-IPContinent"),
+    region_code: "CF-Region-Code",
+    region_name: "CF-Region",
+    city: "CF-IPCity",
+    postal_code: "CF-Postal-Code",
+    latitude: "CF-IPLatitude",
+    longitude: "CF-IPLongitude",
+    metro_code: "CF-Metro-Code",
+    time_zone: "CF-Timezone",
+    asn: None,
+};
+
+const CLOUDFRONT: CdnHeaders = CdnHeaders {
+    country: "CloudFront-Viewer-Country",
+    country_name: Some("CloudFront-Viewer-Country-Name"),
+    continent: None,
+    region_code: "CloudFront-Viewer-Country-Region",
+    region_name: "CloudFront-Viewer-Country-Region-Name",
+    city: "CloudFront-Viewer-City",
+    postal_code: "CloudFront-Viewer-Postal-Code",
+    latitude: "CloudFront-Viewer-Latitude",
+    longitude: "CloudFront-Viewer-Longitude",
+    metro_code: "CloudFront-Viewer-Metro-Code",
+    time_zone: "CloudFront-Viewer-Time-Zone",
+    asn: Some("CloudFront-Viewer-ASN"),
+};
+
+fn cdn_headers(cdn: CdnPreset) -> Option<CdnHeaders> {
+    match cdn {
+        CdnPreset::Cloudflare => Some(CLOUDFLARE),
+        CdnPreset::CloudFront => Some(CLOUDFRONT),
+        CdnPreset::Akamai | CdnPreset::Fastly => None,
+    }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn parsed<T: FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    header(headers, name).and_then(|value| value.parse().ok())
+}
+
+fn english(name: Option<String>) -> Names {
+    name.map(|name| Names::from([("en".to_string(), name)]))
+        .unwrap_or_default()
+}
+
+/// Record built from the geo headers `cdn` adds to the requests, `None` when it sent none
+pub fn record_from_headers(cdn: CdnPreset, headers: &HeaderMap) -> Option<GeoRecord> {
+    let names = cdn_headers(cdn)?;
+
+    // `XX` is sent for unknown countries and `T1` for Tor exit nodes
+    let country_code = header(headers, names.country)
+        .filter(|code| code.len() == 2 && code != "XX" && code != "T1");
+    let country_name = names.country_name.and_then(|name| header(headers, name));
+    let country = (country_code.is_some() || country_name.is_some()).then(|| Country {
+        iso_code: country_code.map(|code| code.to_uppercase()),
+        names: english(country_name),
+        ..Default::default()
+    });
+
+    let continent = names
+        .continent
+        .and_then(|name| header(headers, name))
+        .map(|code| Continent {
+            code: Some(code),
+            ..Default::default()
+        });
+
+    let region_code = header(headers, names.region_code);
+    let region_name = header(headers, names.region_name);
+    let subdivisions = if region_code.is_some() || region_name.is_some() {
+        vec![Subdivision {
+            iso_code: region_code,
+            names: english(region_name),
+            ..Default::default()
+        }]
+    } else {
+        Vec::new()
+    };
+
+    let city = header(headers, names.city).map(|city| City {
+        names: english(Some(city)),
+        ..Default::default()
+    });
+
+    let postal = header(headers, names.postal_code).map(|code| Postal {
+        code: Some(code),
+        ..Default::default()
+    });
+
+    let location = Location {
+        latitude: parsed(headers, names.latitude),
+        longitude: parsed(headers, names.longitude),
+        metro_code: parsed(headers, names.metro_code),
+        time_zone: header(headers, names.time_zone),
+        ..Default::default()
+    };
+    let location = (location != Location::default()).then_some(location);
+
+    let traits = names
+        .asn
+        .and_then(|name| parsed(headers, name))
+        .map(|asn| Traits {
+            autonomous_system_number: Some(asn),
+            ..Default::default()
+        });
+
+    let record = GeoRecord {
+        city,
+        continent,
+        country,
+        location,
+        postal,
+        subdivisions,
+        traits,
+        ..Default::default()
+    };
+    (record != GeoRecord::default()).then_some(record)
+}
+
+/// Answers with the record read from the geo headers of the CDN that sent the request, meant to
+/// be chained before the configured resolvers
+pub struct CdnResolver {
+    name: &'static str,
+    record: Arc<GeoRecord>,
+}
+
+impl CdnResolver {
+    pub fn new(cdn: CdnPreset, record: GeoRecord) -> Self {
+        CdnResolver {
+            name: match cdn {
+                CdnPreset::Cloudflare => "cdn:cloudflare",
+                CdnPreset::Akamai => "cdn:akamai",
+                CdnPreset::Fastly => "cdn:fastly",
+                CdnPreset::CloudFront => "cdn:cloudfront",
+            },
+            record: Arc::new(record),
+        }
+    }
+}
+
+#[async_trait]
+impl GeoResolver for CdnResolver {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn lookup(&self, _addr: &IpAddr, _tier: ServiceTier) -> Result<GeoLookup, GeoError> {
+        Ok(GeoLookup {
+            record: self.record.clone(),
+            source: self.name,
+            field_sources: Vec::new(),
+            cached: false,
+        })
+    }
+}
+
+/// Resolver answering with the record read from the headers of `cdn`, asking `resolver` for
+/// the mapped fields the CDN did not send
+pub fn chain_with_cdn(
+    cdn: CdnPreset,
+    record: GeoRecord,
+    resolver: Arc<dyn GeoResolver>,
+    mapping: &HeaderMapping,
+) -> Arc<dyn GeoResolver> {
+    Arc::new(ChainResolver::new(
+        vec![Arc::new(CdnResolver::new(cdn, record)), resolver],
+        mapping.fields.iter().map(|(field, _)| *field).collect(),
+        &mapping.locale,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chain_with_cdn, record_from_headers, CdnResolver};
+    use crate::geo::chain::ChainResolver;
+    use crate::geo::field::{GeoField, GeoValue};
+    use crate::geo::testing::FixedResolver;
+    use crate::geo::{GeoResolver, ServiceTier};
+    use crate::proxy::client_ip::CdnPreset;
+    use crate::proxy::headers::{GeoHeaders, HeaderMapping};
+    use hyper::header::HeaderValue;
+    use hyper::HeaderMap;
+    use serde_json::json;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    #[test]
+    fn cloudfront_headers() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("CloudFront-Viewer-Country", "CA"),
+            ("CloudFront-Viewer-Country-Name", "Canada"),
+            ("CloudFront-Viewer-Country-Region", "QC"),
+            ("CloudFront-Viewer-City", "Montreal"),
+            ("CloudFront-Viewer-Latitude", "45.5088"),
+            ("CloudFront-Viewer-Longitude", "-73.5878"),
+            ("CloudFront-Viewer-Time-Zone", "America/Toronto"),
+            ("CloudFront-Viewer-ASN", "5769"),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+
+        let record = record_from_headers(CdnPreset::CloudFront, &headers).unwrap();
+        assert_eq!(
+            GeoField::CountryName.value(&record, "en"),
+            Some(GeoValue::String("Canada".to_string()))
+        );
+        assert_eq!(
+            GeoField::ProvinceCode.value(&record, "en"),
+            Some(GeoValue::String("CA-QC".to_string()))
+        );
+        assert_eq!(
+            GeoField::CityName.value(&record, "en"),
+            Some(GeoValue::String("Montreal".to_string()))
+        );
+        assert_eq!(
+            GeoField::AutonomousSystemNumber.value(&record, "en"),
+            Some(GeoValue::Integer(5769))
+        );
+        assert_eq!(
+            record.location.as_ref().unwrap().time_zone.as_deref(),
+            Some("America/Toronto")
+        );
+
+        assert_eq!(record_from_headers(CdnPreset::Cloudflare, &headers), None);
+        let mut headers = HeaderMap::new();
+        headers.insert("CF-IPCountry", HeaderValue::from_static("XX"));
+        assert_eq!(record_from_headers(CdnPreset::Cloudflare, &headers), None);
+    }
+
+    #[tokio::test]
+    async fn resolver_only_fills_missing_fields() {
+        let mut headers = HeaderMap::new();
+        headers.insert("CF-IPCountry", HeaderValue::from_static("FR"));
+        headers.insert("CF-IPCity", HeaderValue::from_static("Paris"));
+        let cdn = Arc::new(CdnResolver::new(
+            CdnPreset::Cloudflare,
+            record_from_headers(CdnPreset::Cloudflare, &headers).unwrap(),
+        ));
+        let maxmind = FixedResolver::new(
+            "maxmind",
+            json!({
+                "city": { "names": { "en": "Laval" } },
+                "country": { "iso_code": "CA" },
+                "traits": { "isp": "Videotron" }
+            }),
+        );
+        let addr = IpAddr::from_str("203.0.113.7").unwrap();
+
+        let chain = ChainResolver::new(
+            vec![cdn.clone(), maxmind.clone()],
+            vec![GeoField::CountryIsoCode, GeoField::CityName],
+            "en",
+        );
+        let lookup = chain.lookup(&addr, ServiceTier::City).await.unwrap();
+        assert_eq!(lookup.source, "cdn:cloudflare");
+        assert_eq!(maxmind.hits(), 0);
+
+        let chain = ChainResolver::new(
+            vec![cdn, maxmind.clone()],
+            vec![GeoField::CountryIsoCode, GeoField::CityName, GeoField::Isp],
+            "en",
+        );
+        let lookup = chain.lookup(&addr, ServiceTier::City).await.unwrap();
+        assert_eq!(maxmind.hits(), 1);
+        assert_eq!(
+            GeoField::CityName.value(&lookup.record, "en"),
+            Some(GeoValue::String("Paris".to_string()))
+        );
+        assert_eq!(
+            lookup.record.country.as_ref().unwrap().iso_code.as_deref(),
+            Some("FR")
+        );
+        assert_eq!(lookup.field_source(GeoField::Isp), "maxmind");
+    }
+
+    #[tokio::test]
+    async fn default_mapping_keeps_resolver_fields() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("CF-IPCountry", "CA"),
+            ("CF-IPContinent", "NA"),
+            ("CF-Region-Code", "QC"),
+            ("CF-Region", "Quebec"),
+            ("CF-IPCity", "Montreal"),
+            ("CF-Postal-Code", "H2X"),
+            ("CF-IPLatitude", "45.5088"),
+            ("CF-IPLongitude", "-73.5878"),
+            ("CF-Metro-Code", "0"),
+            ("CF-Timezone", "America/Toronto"),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        let maxmind = FixedResolver::new(
+            "maxmind",
+            json!({
+                "city": { "names": { "en": "Laval" } },
+                "country": { "iso_code": "CA", "names": { "en": "Canada" } },
+                "location": { "latitude": 45.56, "longitude": -73.71, "accuracy_radius": 20 },
+                "traits": { "isp": "Videotron", "network": "24.200.0.0/14" }
+            }),
+        );
+        let mapping = HeaderMapping::default();
+        let resolver = chain_with_cdn(
+            CdnPreset::Cloudflare,
+            record_from_headers(CdnPreset::Cloudflare, &headers).unwrap(),
+            maxmind.clone(),
+            &mapping,
+        );
+
+        let lookup = resolver
+            .lookup(&IpAddr::from_str("203.0.113.7").unwrap(), ServiceTier::City)
+            .await
+            .unwrap();
+        assert_eq!(maxmind.hits(), 1);
+        assert_eq!(lookup.source, "cdn:cloudflare");
+
+        let mut sent = GeoHeaders::default();
+        for header in mapping.values(&lookup.record) {
+            sent.insert(header);
+        }
+        assert_eq!(sent.get("Prux-City").as_deref(), Some("Montreal"));
+        assert_eq!(sent.get("Prux-Country").as_deref(), Some("Canada"));
+        assert_eq!(sent.get("Prux-ISP").as_deref(), Some("Videotron"));
+        assert_eq!(sent.get("Prux-Network").as_deref(), Some("24.200.0.0/14"));
+        assert_eq!(sent.get("Prux-Coord-Accuracy").as_deref(), Some("20"));
+        assert_eq!(lookup.field_source(GeoField::Isp), "maxmind");
+        assert_eq!(lookup.field_source(GeoField::CityName), "cdn:cloudflare");
+    }
+}
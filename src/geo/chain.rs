@@ -0,0 +1,193 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{debug, error};
+
+use crate::geo::field::GeoField;
+use crate::geo::{GeoError, GeoLookup, GeoRecord, GeoResolver, ServiceTier};
+
+/// Asks several resolvers in order, filling the fields missing from the first answers with the
+/// ones of the next resolvers. Resolvers further down the chain are only asked while a wanted
+/// field is still missing.
+pub struct ChainResolver {
+    resolvers: Vec<Arc<dyn GeoResolver>>,
+    wanted: Vec<GeoField>,
+    locale: String,
+}
+
+impl ChainResolver {
+    /// `wanted` are the fields that are sent upstream, names being looked up in `locale`
+    pub fn new(resolvers: Vec<Arc<dyn GeoResolver>>, wanted: Vec<GeoField>, locale: &str) -> Self {
+        ChainResolver {
+            resolvers,
+            wanted,
+            locale: locale.to_string(),
+        }
+    }
+
+    /// Fills the fields missing from `merged` with the ones of `lookup`, keeping track of where
+    /// each of them came from
+    fn merge(&self, merged: &mut GeoLookup, lookup: &GeoLookup) {
+        let missing = GeoField::ALL
+            .into_iter()
+            .filter(|field| field.value(&merged.record, &self.locale).is_none())
+            .collect::<Vec<_>>();
+
+        Arc::make_mut(&mut merged.record).merge(&lookup.record);
+        merged.cached &= lookup.cached;
+
+        for field in missing {
+            if field.value(&merged.record, &self.locale).is_some() {
+                merged
+                    .field_sources
+                    .push((field, lookup.field_source(field)));
+            }
+        }
+    }
+
+    fn is_complete(&self, record: &GeoRecord) -> bool {
+        self.wanted
+            .iter()
+            .all(|field| field.value(record, &self.locale).is_some())
+    }
+}
+
+#[async_trait]
+impl GeoResolver for ChainResolver {
+    fn name(&self) -> &'static str {
+        "chain"
+    }
+
+    async fn lookup(&self, addr: &IpAddr, tier: ServiceTier) -> Result<GeoLookup, GeoError> {
+        let mut merged: Option<GeoLookup> = None;
+        let mut failure: Option<GeoError> = None;
+
+        for resolver in &self.resolvers {
+            let lookup = match resolver.lookup(addr, tier).await {
+                Ok(lookup) => lookup,
+                Err(e) => {
+                    debug!("{} lookup for {} failed: {}", resolver.name(), addr, e);
+                    // A provider being down says more than another one not knowing the address
+                    if failure.as_ref().is_none_or(GeoError::is_permanent) {
+                        failure = Some(e);
+                    }
+                    continue;
+                }
+            };
+
+            let merged = match merged {
+                Some(ref mut merged) => {
+                    self.merge(merged, &lookup);
+                    merged
+                }
+                None => merged.insert(lookup),
+            };
+
+            if self.is_complete(&merged.record) {
+                break;
+            }
+        }
+
+        let merged = merged.ok_or_else(|| failure.unwrap_or(GeoError::AddressNotFound))?;
+        if !merged.field_sources.is_empty() {
+            debug!(
+                "Resolved {} from {}, completed with {}",
+                addr,
+                merged.source,
+                merged
+                    .field_sources
+                    .iter()
+                    .map(|(field, source)| format!("{}={}", field, source))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        Ok(merged)
+    }
+
+    async fn reload(&self, force: bool) -> Result<bool, String> {
+        // One source failing to reload must not keep the others from being reloaded
+        let mut reloaded = false;
+        for resolver in &self.resolvers {
+            match resolver.reload(force).await {
+                Ok(swapped) => reloaded |= swapped,
+                Err(e) => error!(
+                    "Reload of {} failed, keeping current data: {}",
+                    resolver.name(),
+                    e
+                ),
+            }
+        }
+        Ok(reloaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChainResolver;
+    use crate::geo::field::GeoField;
+    use crate::geo::testing::FixedResolver;
+    use crate::geo::{GeoError, GeoResolver, ServiceTier};
+    use serde_json::json;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    fn addr() -> IpAddr {
+        IpAddr::from_str("24.201.0.1").unwrap()
+    }
+
+    #[tokio::test]
+    async fn fills_missing_fields_in_order() {
+        let city = FixedResolver::new(
+            "mmdb",
+            json!({ "city": { "names": { "en": "Montreal" } }, "country": { "iso_code": "CA" } }),
+        );
+        let asn = FixedResolver::new(
+            "mmdb:GeoLite2-ASN",
+            json!({ "traits": { "autonomous_system_number": 5769 }, "country": { "iso_code": "US" } }),
+        );
+        let web = FixedResolver::new("maxmind", json!({ "traits": { "isp": "Videotron" } }));
+        let chain = ChainResolver::new(
+            vec![city, asn, web.clone()],
+            vec![GeoField::CityName, GeoField::AutonomousSystemNumber],
+            "en",
+        );
+
+        let lookup = chain.lookup(&addr(), ServiceTier::City).await.unwrap();
+        assert_eq!(lookup.source, "mmdb");
+        assert_eq!(lookup.field_source(GeoField::CityName), "mmdb");
+        assert_eq!(
+            lookup.field_source(GeoField::AutonomousSystemNumber),
+            "mmdb:GeoLite2-ASN"
+        );
+        assert_eq!(
+            lookup.record.country.as_ref().unwrap().iso_code.as_deref(),
+            Some("CA")
+        );
+        // Every wanted field was found before reaching the web service
+        assert_eq!(web.hits(), 0);
+    }
+
+    #[tokio::test]
+    async fn skips_failing_resolvers() {
+        let down = FixedResolver::failing("maxmind", GeoError::Timeout);
+        let unknown = FixedResolver::failing("mmdb", GeoError::AddressNotFound);
+        let chain = ChainResolver::new(
+            vec![unknown.clone(), down.clone()],
+            vec![GeoField::CityName],
+            "en",
+        );
+        assert_eq!(
+            chain.lookup(&addr(), ServiceTier::City).await.unwrap_err(),
+            GeoError::Timeout
+        );
+
+        let city = FixedResolver::new("table", json!({ "city": { "names": { "en": "Laval" } } }));
+        let chain = ChainResolver::new(vec![down, unknown, city], vec![GeoField::CityName], "en");
+        let lookup = chain.lookup(&addr(), ServiceTier::City).await.unwrap();
+        assert_eq!(lookup.source, "table");
+        assert_eq!(lookup.field_source(GeoField::CityName), "table");
+    }
+}
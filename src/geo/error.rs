@@ -0,0 +1,208 @@
+use std::fmt;
+
+use hyper::StatusCode;
+
+/// Error body returned by the MaxMind web services
+#[derive(Debug, Deserialize)]
+struct MaxmindErrorBody {
+    code: String,
+    error: String,
+}
+
+/// Reasons a geo lookup can fail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GeoError {
+    /// The address is not in the provider's data
+    AddressNotFound,
+    /// The address belongs to a reserved or private range
+    AddressReserved,
+    /// The provider could not parse the address
+    AddressInvalid,
+    /// The account id or license key is missing or wrong
+    AuthorizationInvalid(String),
+    /// The account has no queries left
+    OutOfQueries,
+    /// The account is not allowed to use the requested service
+    PermissionRequired(String),
+    /// Any other error reported by the provider
+    Provider {
+        status: u16,
+        code: Option<String>,
+        error: Option<String>,
+    },
+    /// The provider did not answer in time
+    Timeout,
+    /// The provider is not called while its circuit breaker is open
+    CircuitOpen,
+    /// The provider could not be reached
+    Transport(String),
+    /// The provider answered with something that is not a geo record
+    Json(String),
+    /// The local geo database could not be read
+    Database(String),
+}
+
+impl GeoError {
+    /// Builds the error matching a non successful MaxMind response
+    pub fn from_response(status: StatusCode, body: &[u8]) -> Self {
+        let body = match serde_json::from_slice::<MaxmindErrorBody>(body) {
+            Ok(body) => body,
+            // Without a MaxMind code the status alone cannot tell a bad address from a bad
+            // route or an intermediate proxy, so it is never taken as permanent
+            Err(_) => {
+                return GeoError::Provider {
+                    status: status.as_u16(),
+                    code: None,
+                    error: Some(String::from_utf8_lossy(body).into_owned())
+                        .filter(|body| !body.is_empty()),
+                }
+            }
+        };
+
+        match body.code.as_str() {
+            "IP_ADDRESS_NOT_FOUND" => GeoError::AddressNotFound,
+            "IP_ADDRESS_RESERVED" => GeoError::AddressReserved,
+            "IP_ADDRESS_INVALID" | "IP_ADDRESS_REQUIRED" => GeoError::AddressInvalid,
+            "AUTHORIZATION_INVALID"
+            | "ACCOUNT_ID_REQUIRED"
+            | "ACCOUNT_ID_UNKNOWN"
+            | "LICENSE_KEY_REQUIRED"
+            | "USER_ID_REQUIRED"
+            | "USER_ID_UNKNOWN" => GeoError::AuthorizationInvalid(body.error),
+            "INSUFFICIENT_FUNDS" | "OUT_OF_QUERIES" => GeoError::OutOfQueries,
+            "PERMISSION_REQUIRED" => GeoError::PermissionRequired(body.error),
+            _ => GeoError::Provider {
+                status: status.as_u16(),
+                code: Some(body.code),
+                error: Some(body.error),
+            },
+        }
+    }
+
+    /// Whether asking again for the same address will fail the same way
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            GeoError::AddressNotFound | GeoError::AddressReserved | GeoError::AddressInvalid
+        )
+    }
+
+    /// Whether a new attempt right away may succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            GeoError::Timeout | GeoError::Transport(_) => true,
+            GeoError::Provider { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// Whether the failure needs an operator to fix the provider account or configuration
+    pub fn needs_attention(&self) -> bool {
+        matches!(
+            self,
+            GeoError::AuthorizationInvalid(_)
+                | GeoError::OutOfQueries
+                | GeoError::PermissionRequired(_)
+                | GeoError::Database(_)
+        )
+    }
+}
+
+impl fmt::Display for GeoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoError::AddressNotFound => write!(f, "address not found"),
+            GeoError::AddressReserved => write!(f, "address is reserved"),
+            GeoError::AddressInvalid => write!(f, "address is invalid"),
+            GeoError::AuthorizationInvalid(e) => write!(f, "authorization invalid: {}", e),
+            GeoError::OutOfQueries => write!(f, "out of queries"),
+            GeoError::PermissionRequired(e) => write!(f, "permission required: {}", e),
+            GeoError::Provider {
+                status,
+                code,
+                error,
+            } => write!(
+                f,
+                "provider error (status {}, code {}): {}",
+                status,
+                code.as_deref().unwrap_or("none"),
+                error.as_deref().unwrap_or("no details")
+            ),
+            GeoError::Timeout => write!(f, "lookup timed out"),
+            GeoError::CircuitOpen => write!(f, "circuit breaker is open"),
+            GeoError::Transport(e) => write!(f, "transport error: {}", e),
+            GeoError::Json(e) => write!(f, "invalid response: {}", e),
+            GeoError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GeoError {}
+
+impl From<hyper::Error> for GeoError {
+    fn from(e: hyper::Error) -> Self {
+        if e.is_timeout() {
+            GeoError::Timeout
+        } else {
+            GeoError::Transport(e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GeoError;
+    use hyper::StatusCode;
+
+    #[test]
+    fn error_from_maxmind_body() {
+        assert_eq!(
+            GeoError::from_response(
+                StatusCode::NOT_FOUND,
+                br#"{"code": "IP_ADDRESS_NOT_FOUND", "error": "The value 1.2.3.4 is not in the database."}"#
+            ),
+            GeoError::AddressNotFound
+        );
+        assert_eq!(
+            GeoError::from_response(
+                StatusCode::PAYMENT_REQUIRED,
+                br#"{"code": "OUT_OF_QUERIES", "error": "The license key you have provided is out of queries."}"#
+            ),
+            GeoError::OutOfQueries
+        );
+        assert_eq!(
+            GeoError::from_response(
+                StatusCode::UNAUTHORIZED,
+                br#"{"code": "AUTHORIZATION_INVALID", "error": "bad key"}"#
+            ),
+            GeoError::AuthorizationInvalid("bad key".to_string())
+        );
+        assert_eq!(
+            GeoError::from_response(StatusCode::NOT_FOUND, b""),
+            GeoError::Provider {
+                status: 404,
+                code: None,
+                error: None
+            }
+        );
+        assert!(!GeoError::from_response(StatusCode::BAD_REQUEST, b"bad request").is_permanent());
+        assert_eq!(
+            GeoError::from_response(StatusCode::BAD_GATEWAY, b"<html></html>"),
+            GeoError::Provider {
+                status: 502,
+                code: None,
+                error: Some("<html></html>".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn permanent_errors() {
+        assert!(GeoError::AddressReserved.is_permanent());
+        assert!(!GeoError::Timeout.is_permanent());
+        assert!(!GeoError::OutOfQueries.is_permanent());
+        assert!(GeoError::OutOfQueries.needs_attention());
+        assert!(GeoError::Timeout.is_retryable());
+        assert!(!GeoError::OutOfQueries.is_retryable());
+    }
+}
@@ -0,0 +1,315 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::geo::record::Subdivision;
+use crate::geo::GeoRecord;
+
+/// A GeoIP2 record field that can be sent upstream as a header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GeoField {
+    CityName,
+    CityConfidence,
+    CityGeonameId,
+    ContinentCode,
+    ContinentName,
+    CountryName,
+    CountryIsoCode,
+    CountryConfidence,
+    IsInEuropeanUnion,
+    RegisteredCountryName,
+    RegisteredCountryIsoCode,
+    /// Least specific subdivision, the province or state
+    ProvinceIsoCode,
+    ProvinceName,
+    ProvinceCode,
+    ProvinceConfidence,
+    /// Most specific subdivision
+    SubdivisionIsoCode,
+    SubdivisionName,
+    SubdivisionCode,
+    SubdivisionConfidence,
+    PostalCode,
+    PostalConfidence,
+    Coordinates,
+    Latitude,
+    Longitude,
+    AccuracyRadius,
+    MetroCode,
+    TimeZone,
+    AutonomousSystemNumber,
+    AutonomousSystemOrganization,
+    ConnectionType,
+    Domain,
+    Isp,
+    Organization,
+    UserType,
+    Network,
+    /// Custom labels of the network overrides, as `name=value` pairs
+    Labels,
+}
+
+impl GeoField {
+    pub const ALL: [GeoField; 36] = [
+        GeoField::CityName,
+        GeoField::CityConfidence,
+        GeoField::CityGeonameId,
+        GeoField::ContinentCode,
+        GeoField::ContinentName,
+        GeoField::CountryName,
+        GeoField::CountryIsoCode,
+        GeoField::CountryConfidence,
+        GeoField::IsInEuropeanUnion,
+        GeoField::RegisteredCountryName,
+        GeoField::RegisteredCountryIsoCode,
+        GeoField::ProvinceIsoCode,
+        GeoField::ProvinceName,
+        GeoField::ProvinceCode,
+        GeoField::ProvinceConfidence,
+        GeoField::SubdivisionIsoCode,
+        GeoField::SubdivisionName,
+        GeoField::SubdivisionCode,
+        GeoField::SubdivisionConfidence,
+        GeoField::PostalCode,
+        GeoField::PostalConfidence,
+        GeoField::Coordinates,
+        GeoField::Latitude,
+        GeoField::Longitude,
+        GeoField::AccuracyRadius,
+        GeoField::MetroCode,
+        GeoField::TimeZone,
+        GeoField::AutonomousSystemNumber,
+        GeoField::AutonomousSystemOrganization,
+        GeoField::ConnectionType,
+        GeoField::Domain,
+        GeoField::Isp,
+        GeoField::Organization,
+        GeoField::UserType,
+        GeoField::Network,
+        GeoField::Labels,
+    ];
+
+    /// Name of the field in the `geo_headers` configuration
+    pub fn name(&self) -> &'static str {
+        match self {
+            GeoField::CityName => "city_name",
+            GeoField::CityConfidence => "city_confidence",
+            GeoField::CityGeonameId => "city_geoname_id",
+            GeoField::ContinentCode => "continent_code",
+            GeoField::ContinentName => "continent_name",
+            GeoField::CountryName => "country_name",
+            GeoField::CountryIsoCode => "country_iso_code",
+            GeoField::CountryConfidence => "country_confidence",
+            GeoField::IsInEuropeanUnion => "is_in_european_union",
+            GeoField::RegisteredCountryName => "registered_country_name",
+            GeoField::RegisteredCountryIsoCode => "registered_country_iso_code",
+            GeoField::ProvinceIsoCode => "province_iso_code",
+            GeoField::ProvinceName => "province_name",
+            GeoField::ProvinceCode => "province_code",
+            GeoField::ProvinceConfidence => "province_confidence",
+            GeoField::SubdivisionIsoCode => "subdivision_iso_code",
+            GeoField::SubdivisionName => "subdivision_name",
+            GeoField::SubdivisionCode => "subdivision_code",
+            GeoField::SubdivisionConfidence => "subdivision_confidence",
+            GeoField::PostalCode => "postal_code",
+            GeoField::PostalConfidence => "postal_confidence",
+            GeoField::Coordinates => "coordinates",
+            GeoField::Latitude => "latitude",
+            GeoField::Longitude => "longitude",
+            GeoField::AccuracyRadius => "accuracy_radius",
+            GeoField::MetroCode => "metro_code",
+            GeoField::TimeZone => "time_zone",
+            GeoField::AutonomousSystemNumber => "autonomous_system_number",
+            GeoField::AutonomousSystemOrganization => "autonomous_system_organization",
+            GeoField::ConnectionType => "connection_type",
+            GeoField::Domain => "domain",
+            GeoField::Isp => "isp",
+            GeoField::Organization => "organization",
+            GeoField::UserType => "user_type",
+            GeoField::Network => "network",
+            GeoField::Labels => "labels",
+        }
+    }
+
+    /// Header sent for the field when the configuration does not say otherwise
+    pub fn default_header(&self) -> Option<&'static str> {
+        match self {
+            GeoField::CityName => Some("Prux-City"),
+            GeoField::CountryName => Some("Prux-Country"),
+            GeoField::ProvinceIsoCode => Some("Prux-Province"),
+            GeoField::ProvinceName => Some("Prux-Province-Name"),
+            GeoField::ProvinceCode => Some("Prux-Province-Code"),
+            GeoField::SubdivisionIsoCode => Some("Prux-Subdivision"),
+            GeoField::SubdivisionName => Some("Prux-Subdivision-Name"),
+            GeoField::SubdivisionCode => Some("Prux-Subdivision-Code"),
+            GeoField::Coordinates => Some("Prux-Coord"),
+            GeoField::AccuracyRadius => Some("Prux-Coord-Accuracy"),
+            GeoField::TimeZone => Some("Prux-Timezone"),
+            GeoField::Isp => Some("Prux-ISP"),
+            GeoField::Network => Some("Prux-Network"),
+            GeoField::Labels => Some("Prux-Labels"),
+            _ => None,
+        }
+    }
+
+    /// Value of the field in `record`, names are taken in `locale` when available
+    pub fn value(&self, record: &GeoRecord, locale: &str) -> Option<GeoValue> {
+        let province = record.least_specific_subdivision();
+        let subdivision = record.most_specific_subdivision();
+        let location = record.location.as_ref();
+        let traits = record.traits.as_ref();
+
+        match self {
+            GeoField::CityName => name(record.city.as_ref()?.name(locale)),
+            GeoField::CityConfidence => int(record.city.as_ref()?.confidence),
+            GeoField::CityGeonameId => int(record.city.as_ref()?.geoname_id),
+            GeoField::ContinentCode => string(&record.continent.as_ref()?.code),
+            GeoField::ContinentName => name(record.continent.as_ref()?.name(locale)),
+            GeoField::CountryName => name(record.country.as_ref()?.name(locale)),
+            GeoField::CountryIsoCode => string(&record.country.as_ref()?.iso_code),
+            GeoField::CountryConfidence => int(record.country.as_ref()?.confidence),
+            GeoField::IsInEuropeanUnion => record
+                .country
+                .as_ref()?
+                .is_in_european_union
+                .map(GeoValue::Boolean),
+            GeoField::RegisteredCountryName => {
+                name(record.registered_country.as_ref()?.name(locale))
+            }
+            GeoField::RegisteredCountryIsoCode => {
+                string(&record.registered_country.as_ref()?.iso_code)
+            }
+            GeoField::ProvinceIsoCode => string(&province?.iso_code),
+            GeoField::ProvinceName => name(province?.name(locale)),
+            GeoField::ProvinceCode => iso_3166_2(record, province?),
+            GeoField::ProvinceConfidence => int(province?.confidence),
+            GeoField::SubdivisionIsoCode => string(&subdivision?.iso_code),
+            GeoField::SubdivisionName => name(subdivision?.name(locale)),
+            GeoField::SubdivisionCode => iso_3166_2(record, subdivision?),
+            GeoField::SubdivisionConfidence => int(subdivision?.confidence),
+            GeoField::PostalCode => string(&record.postal.as_ref()?.code),
+            GeoField::PostalConfidence => int(record.postal.as_ref()?.confidence),
+            GeoField::Coordinates => {
+                let location = location?;
+                Some(GeoValue::String(format!(
+                    "{},{}",
+                    location.latitude?, location.longitude?
+                )))
+            }
+            GeoField::Latitude => location?.latitude.map(GeoValue::Decimal),
+            GeoField::Longitude => location?.longitude.map(GeoValue::Decimal),
+            GeoField::AccuracyRadius => int(location?.accuracy_radius),
+            GeoField::MetroCode => int(location?.metro_code),
+            GeoField::TimeZone => string(&location?.time_zone),
+            GeoField::AutonomousSystemNumber => int(traits?.autonomous_system_number),
+            GeoField::AutonomousSystemOrganization => {
+                string(&traits?.autonomous_system_organization)
+            }
+            GeoField::ConnectionType => string(&traits?.connection_type),
+            GeoField::Domain => string(&traits?.domain),
+            GeoField::Isp => string(&traits?.isp),
+            GeoField::Organization => string(&traits?.organization),
+            GeoField::UserType => string(&traits?.user_type),
+            GeoField::Network => string(&traits?.network),
+            GeoField::Labels => (!record.labels.is_empty()).then(|| {
+                GeoValue::String(
+                    record
+                        .labels
+                        .iter()
+                        .map(|(name, value)| format!("{}={}", name, value))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                )
+            }),
+        }
+    }
+}
+
+fn name(name: Option<&str>) -> Option<GeoValue> {
+    name.map(|name| GeoValue::String(name.to_string()))
+}
+
+fn string(value: &Option<String>) -> Option<GeoValue> {
+    value.clone().map(GeoValue::String)
+}
+
+fn int<T: Into<i64>>(value: Option<T>) -> Option<GeoValue> {
+    value.map(|value| GeoValue::Integer(value.into()))
+}
+
+fn iso_3166_2(record: &GeoRecord, subdivision: &Subdivision) -> Option<GeoValue> {
+    record.iso_3166_2(subdivision).map(GeoValue::String)
+}
+
+impl fmt::Display for GeoField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for GeoField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        GeoField::ALL
+            .iter()
+            .find(|field| field.name() == s)
+            .copied()
+            .ok_or_else(|| format!("Unknown geo field: {}", s))
+    }
+}
+
+/// Typed value of a geo field
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeoValue {
+    String(String),
+    Integer(i64),
+    Decimal(f64),
+    Boolean(bool),
+}
+
+impl fmt::Display for GeoValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoValue::String(s) => write!(f, "{}", s),
+            GeoValue::Integer(i) => write!(f, "{}", i),
+            GeoValue::Decimal(d) => write!(f, "{}", d),
+            GeoValue::Boolean(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GeoField, GeoValue};
+    use crate::geo::GeoRecord;
+    use serde_json::json;
+
+    fn record() -> GeoRecord {
+        serde_json::from_value(json!({
+            "continent": { "code": "EU", "names": { "en": "Europe" } },
+            "country": { "iso_code": "FR", "is_in_european_union": true, "names": { "en": "France" } },
+            "traits": { "autonomous_system_number": 3215, "isp": "Orange" }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn field_values() {
+        let record = record();
+        assert_eq!(
+            GeoField::IsInEuropeanUnion.value(&record, "en"),
+            Some(GeoValue::Boolean(true))
+        );
+        assert_eq!(
+            GeoField::AutonomousSystemNumber.value(&record, "en"),
+            Some(GeoValue::Integer(3215))
+        );
+        assert_eq!(
+            GeoField::ContinentName.value(&record, "fr"),
+            Some(GeoValue::String("Europe".to_string()))
+        );
+        assert_eq!(GeoField::CityName.value(&record, "en"), None);
+        assert_eq!(GeoField::Coordinates.value(&record, "en"), None);
+    }
+}
@@ -0,0 +1,370 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use ipnetwork::IpNetwork;
+use log::{debug, info};
+use maxminddb::{MaxMindDBError, Reader};
+use parking_lot::{Mutex, RwLock};
+
+use crate::geo::record::Traits;
+use crate::geo::{GeoError, GeoLookup, GeoRecord, GeoResolver, ServiceTier};
+
+/// Database types whose records follow the GeoIP2 City/Country model
+const GEO_DATABASE_TYPES: &[&str] = &["City", "Country", "Enterprise"];
+/// Database types whose records only hold network traits
+const TRAITS_DATABASE_TYPES: &[&str] = &["ASN", "ISP", "Connection-Type", "Domain"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DatabaseKind {
+    Geo,
+    Traits,
+}
+
+/// Resolver backed by a local GeoLite2/GeoIP2 database file, either a City/Country database or
+/// one of the ASN, ISP, Connection-Type and Domain databases
+pub struct MmdbResolver {
+    name: &'static str,
+    path: PathBuf,
+    reader: RwLock<Arc<(Reader<Vec<u8>>, DatabaseKind)>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl MmdbResolver {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let database = load_database(&path)?;
+
+        Ok(MmdbResolver {
+            name: "mmdb",
+            modified: Mutex::new(modified_time(&path)),
+            reader: RwLock::new(Arc::new(database)),
+            path,
+        })
+    }
+
+    /// Reports lookups under `name` instead of `mmdb`, to tell several databases apart
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Opens the database at `path` and checks that it can actually serve lookups
+fn load_database(path: &Path) -> Result<(Reader<Vec<u8>>, DatabaseKind), String> {
+    let reader = Reader::open_readfile(path)
+        .map_err(|e| format!("Unable to open geo database {}: {}", path.display(), e))?;
+
+    let database_type = &reader.metadata.database_type;
+    let is_type = |types: &[&str]| types.iter().any(|t| database_type.contains(t));
+    let kind = if is_type(GEO_DATABASE_TYPES) {
+        DatabaseKind::Geo
+    } else if is_type(TRAITS_DATABASE_TYPES) {
+        DatabaseKind::Traits
+    } else {
+        return Err(format!(
+            "Geo database {} is a {} database, which prux cannot use",
+            path.display(),
+            database_type
+        ));
+    };
+
+    // Walk the tree once so a truncated or corrupted file is refused before it gets swapped in
+    match lookup_record(&reader, kind, IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))) {
+        Ok(_) | Err(MaxMindDBError::AddressNotFoundError(_)) => {}
+        Err(e) => return Err(format!("Geo database {} is invalid: {}", path.display(), e)),
+    }
+
+    debug!(
+        "Loaded {} database built at {}",
+        database_type, reader.metadata.build_epoch
+    );
+
+    Ok((reader, kind))
+}
+
+fn lookup_record(
+    reader: &Reader<Vec<u8>>,
+    kind: DatabaseKind,
+    addr: IpAddr,
+) -> Result<(GeoRecord, usize), MaxMindDBError> {
+    match kind {
+        DatabaseKind::Geo => reader.lookup_prefix::<GeoRecord>(addr),
+        DatabaseKind::Traits => reader.lookup_prefix::<Traits>(addr).map(|(traits, len)| {
+            let record = GeoRecord {
+                traits: Some(traits),
+                ..Default::default()
+            };
+            (record, len)
+        }),
+    }
+}
+
+#[async_trait]
+impl GeoResolver for MmdbResolver {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn lookup(&self, addr: &IpAddr, _tier: ServiceTier) -> Result<GeoLookup, GeoError> {
+        let database = self.reader.read().clone();
+        let (mut record, prefix_len) =
+            lookup_record(&database.0, database.1, *addr).map_err(|e| match e {
+                MaxMindDBError::AddressNotFoundError(_) => GeoError::AddressNotFound,
+                // Raised when looking up an IPv6 address in an IPv4 only database
+                MaxMindDBError::InvalidNetworkError(_) => GeoError::AddressInvalid,
+                e => GeoError::Database(e.to_string()),
+            })?;
+
+        // The web service reports the matched network in traits, the database only gives its prefix
+        if let Ok(network) = IpNetwork::new(*addr, prefix_len as u8) {
+            record
+                .traits
+                .get_or_insert_with(Traits::default)
+                .network
+                .get_or_insert_with(|| format!("{}/{}", network.network(), network.prefix()));
+        }
+
+        Ok(GeoLookup {
+            record: Arc::new(record),
+            source: self.name(),
+            field_sources: Vec::new(),
+            cached: false,
+        })
+    }
+
+    async fn reload(&self, force: bool) -> Result<bool, String> {
+        let modified = modified_time(&self.path);
+        if !force && modified == *self.modified.lock() {
+            return Ok(false);
+        }
+
+        let path = self.path.clone();
+        let reader = tokio::task::spawn_blocking(move || load_database(&path))
+            .await
+            .map_err(|e| format!("Geo database reload task failed: {}", e))??;
+
+        *self.reader.write() = Arc::new(reader);
+        *self.modified.lock() = modified;
+        info!("Reloaded geo database {}", self.path.display());
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::MmdbResolver;
+    use crate::geo::{GeoResolver, ServiceTier};
+    use serde_json::{json, Value};
+    use std::net::IpAddr;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+
+    fn encode_control(out: &mut Vec<u8>, type_num: u8, size: usize) {
+        assert!(size < 29 + 256, "test encoder only supports small values");
+        let size_bits = size.min(29) as u8;
+        if type_num > 7 {
+            out.push(size_bits);
+            out.push(type_num - 7);
+        } else {
+            out.push((type_num << 5) | size_bits);
+        }
+        if size >= 29 {
+            out.push((size - 29) as u8);
+        }
+    }
+
+    fn encode_value(out: &mut Vec<u8>, value: &Value) {
+        match value {
+            Value::Object(map) => {
+                encode_control(out, 7, map.len());
+                for (key, value) in map {
+                    encode_value(out, &Value::String(key.clone()));
+                    encode_value(out, value);
+                }
+            }
+            Value::Array(items) => {
+                encode_control(out, 11, items.len());
+                for item in items {
+                    encode_value(out, item);
+                }
+            }
+            Value::String(s) => {
+                encode_control(out, 2, s.len());
+                out.extend_from_slice(s.as_bytes());
+            }
+            Value::Bool(b) => encode_control(out, 14, *b as usize),
+            Value::Number(n) if n.is_u64() => {
+                encode_control(out, 6, 4);
+                out.extend_from_slice(&(n.as_u64().unwrap() as u32).to_be_bytes());
+            }
+            Value::Number(n) => {
+                encode_control(out, 3, 8);
+                out.extend_from_slice(&n.as_f64().unwrap().to_be_bytes());
+            }
+            Value::Null => panic!("null is not representable in a maxmind database"),
+        }
+    }
+
+    /// Writes an IPv4 City database where every address resolves to `record`
+    pub(crate) fn write_test_database(path: &Path, record: &Value) {
+        write_typed_test_database(path, "GeoLite2-City", record)
+    }
+
+    /// Writes an IPv4 `database_type` database where every address resolves to `record`
+    pub(crate) fn write_typed_test_database(path: &Path, database_type: &str, record: &Value) {
+        // Single node tree: both branches point to the first data record
+        let node_count: u32 = 1;
+        let data_pointer = (node_count + 16).to_be_bytes();
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&data_pointer[1..]);
+        buf.extend_from_slice(&data_pointer[1..]);
+        buf.extend_from_slice(&[0; 16]);
+        encode_value(&mut buf, record);
+        buf.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        encode_value(
+            &mut buf,
+            &json!({
+                "binary_format_major_version": 2,
+                "binary_format_minor_version": 0,
+                "build_epoch": 1,
+                "database_type": database_type,
+                "description": { "en": "prux test database" },
+                "ip_version": 4,
+                "languages": ["en"],
+                "node_count": node_count,
+                "record_size": 24
+            }),
+        );
+        std::fs::write(path, buf).unwrap();
+    }
+
+    pub(crate) fn test_database_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("prux-{}-{}.mmdb", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn lookup_from_database() {
+        let path = test_database_path("lookup");
+        write_test_database(
+            &path,
+            &json!({
+                "city": { "names": { "en": "Montreal" } },
+                "country": { "iso_code": "CA", "names": { "en": "Canada" } },
+                "location": { "latitude": 45.5, "longitude": -73.58, "accuracy_radius": 5 }
+            }),
+        );
+
+        let resolver = MmdbResolver::open(&path).unwrap();
+        let lookup = resolver
+            .lookup(&IpAddr::from_str("142.44.0.1").unwrap(), ServiceTier::City)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let record = lookup.record;
+        assert_eq!(lookup.source, "mmdb");
+        assert_eq!(record.city.as_ref().unwrap().name("en"), Some("Montreal"));
+        assert_eq!(record.location.as_ref().unwrap().accuracy_radius, Some(5));
+        assert_eq!(
+            record.traits.as_ref().unwrap().network.as_deref(),
+            Some("128.0.0.0/1")
+        );
+    }
+
+    #[tokio::test]
+    async fn lookup_from_asn_database() {
+        let path = test_database_path("asn");
+        write_typed_test_database(
+            &path,
+            "GeoLite2-ASN",
+            &json!({
+                "autonomous_system_number": 5769,
+                "autonomous_system_organization": "Videotron"
+            }),
+        );
+
+        let resolver = MmdbResolver::open(&path)
+            .unwrap()
+            .with_name("mmdb:GeoLite2-ASN");
+        let lookup = resolver
+            .lookup(&IpAddr::from_str("24.201.0.1").unwrap(), ServiceTier::City)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let traits = lookup.record.traits.as_ref().unwrap();
+        assert_eq!(lookup.source, "mmdb:GeoLite2-ASN");
+        assert_eq!(traits.autonomous_system_number, Some(5769));
+        assert_eq!(
+            traits.autonomous_system_organization.as_deref(),
+            Some("Videotron")
+        );
+        assert!(lookup.record.city.is_none());
+    }
+
+    #[test]
+    fn unsupported_database() {
+        let path = test_database_path("anonymous");
+        write_typed_test_database(
+            &path,
+            "GeoIP2-Anonymous-IP",
+            &json!({ "is_anonymous": true }),
+        );
+        assert!(MmdbResolver::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_database() {
+        assert!(MmdbResolver::open("/nonexistent/GeoLite2-City.mmdb").is_err());
+    }
+
+    #[tokio::test]
+    async fn reload_swaps_database() {
+        let path = test_database_path("reload");
+        write_test_database(&path, &json!({ "city": { "names": { "en": "Montreal" } } }));
+        let resolver = MmdbResolver::open(&path).unwrap();
+        let ip = IpAddr::from_str("142.44.0.1").unwrap();
+
+        assert!(!resolver.reload(false).await.unwrap());
+
+        write_test_database(&path, &json!({ "city": { "names": { "en": "Quebec" } } }));
+        assert!(resolver.reload(true).await.unwrap());
+        assert_eq!(
+            resolver
+                .lookup(&ip, ServiceTier::City)
+                .await
+                .unwrap()
+                .record
+                .city
+                .as_ref()
+                .and_then(|city| city.name("en")),
+            Some("Quebec")
+        );
+
+        // A broken file is refused and the previous database keeps serving
+        std::fs::write(&path, b"not a database").unwrap();
+        assert!(resolver.reload(true).await.is_err());
+        assert_eq!(
+            resolver
+                .lookup(&ip, ServiceTier::City)
+                .await
+                .unwrap()
+                .record
+                .city
+                .as_ref()
+                .and_then(|city| city.name("en")),
+            Some("Quebec")
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{error, info};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::geo::chain::ChainResolver;
+use crate::geo::field::GeoField;
+use crate::geo::mmdb::MmdbResolver;
+use crate::geo::overrides::{NetworkOverrides, OverrideResolver};
+use crate::http::breaker::CircuitBreaker;
+use crate::http::request::{HttpRequest, RetryPolicy};
+use crate::settings::Server;
+
+pub use crate::geo::error::GeoError;
+pub use crate::geo::record::GeoRecord;
+
+pub mod cdn;
+pub mod chain;
+pub mod error;
+pub mod field;
+pub mod mmdb;
+pub mod overrides;
+pub mod record;
+#[cfg(test)]
+pub(crate) mod testing;
+
+/// Result of a successful geo lookup along with where it came from
+#[derive(Debug, Clone)]
+pub struct GeoLookup {
+    /// Geo record as returned by the backend
+    pub record: Arc<GeoRecord>,
+    /// Name of the resolver that produced the record
+    pub source: &'static str,
+    /// Resolver that provided each field, for records merged from several resolvers. Fields not
+    /// listed come from `source`.
+    pub field_sources: Vec<(GeoField, &'static str)>,
+    /// Whether the record was served from the resolver cache
+    pub cached: bool,
+}
+
+impl GeoLookup {
+    /// Name of the resolver that provided `field`
+    pub fn field_source(&self, field: GeoField) -> &'static str {
+        self.field_sources
+            .iter()
+            .find(|(f, _)| *f == field)
+            .map_or(self.source, |(_, source)| source)
+    }
+}
+
+/// MaxMind web service tier, from the cheapest to the most detailed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ServiceTier {
+    Country,
+    City,
+    Insights,
+}
+
+impl fmt::Display for ServiceTier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceTier::Country => write!(f, "country"),
+            ServiceTier::City => write!(f, "city"),
+            ServiceTier::Insights => write!(f, "insights"),
+        }
+    }
+}
+
+impl FromStr for ServiceTier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "country" => Ok(ServiceTier::Country),
+            "city" => Ok(ServiceTier::City),
+            "insights" => Ok(ServiceTier::Insights),
+            other => Err(format!("Unknown maxmind service tier: {}", other)),
+        }
+    }
+}
+
+/// A source of geolocation data for ip addresses
+#[async_trait]
+pub trait GeoResolver: Send + Sync {
+    /// Short name used to report the provenance of a lookup
+    fn name(&self) -> &'static str;
+
+    /// Looks up `addr`, `tier` being the level of detail the caller needs. Backends without
+    /// tiers always answer with everything they know.
+    async fn lookup(&self, addr: &IpAddr, tier: ServiceTier) -> Result<GeoLookup, GeoError>;
+
+    /// Reloads backing data that changed since it was loaded, or unconditionally when `force`
+    /// is set. Returns whether new data was swapped in.
+    async fn reload(&self, _force: bool) -> Result<bool, String> {
+        Ok(false)
+    }
+}
+
+/// Builds the resolvers listed in `Server.resolver`, chained when there are several of them.
+/// `wanted` are the fields sent upstream, which a chain tries to fill. Addresses covered by
+/// `overrides` are answered from the table without asking any resolver.
+pub fn resolver_from_settings(
+    server: &Server,
+    wanted: &[GeoField],
+    overrides: Arc<NetworkOverrides>,
+) -> Result<Arc<dyn GeoResolver>, String> {
+    let mut resolvers = server
+        .resolver
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| resolver_from_name(server, name))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let resolver: Arc<dyn GeoResolver> = match resolvers.len() {
+        0 => return Err("At least one geo resolver is required".to_string()),
+        1 => resolvers.remove(0),
+        _ => Arc::new(ChainResolver::new(
+            resolvers,
+            wanted.to_vec(),
+            &server.geo_locale,
+        )),
+    };
+
+    if overrides.is_empty() {
+        Ok(resolver)
+    } else {
+        Ok(Arc::new(OverrideResolver::new(overrides, resolver)))
+    }
+}
+
+/// Builds a single resolver: `maxmind`, `mmdb` for the database at `Server.mmdb_path`, or
+/// `mmdb:<path>` for any other database
+fn resolver_from_name(server: &Server, name: &str) -> Result<Arc<dyn GeoResolver>, String> {
+    if let Some((kind, path)) = name.split_once(':') {
+        if kind.trim().eq_ignore_ascii_case("mmdb") {
+            let path = Path::new(path.trim());
+            let stem = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy())
+                .unwrap_or_default();
+            // Resolver names are static, this runs once per configured database at startup
+            let name: &'static str = Box::leak(format!("mmdb:{}", stem).into_boxed_str());
+            return Ok(Arc::new(MmdbResolver::open(path)?.with_name(name)));
+        }
+    }
+
+    match name.to_lowercase().as_str() {
+        "maxmind" => Ok(Arc::new(HttpRequest::new(
+            &server.maxmind_id,
+            &server.maxmind_password,
+            &server.maxmind_base_url,
+            &server.maxmind_api_version,
+            server.cache_capacity,
+            Duration::from_secs(server.cache_duration_secs),
+            Duration::from_secs(server.negative_cache_duration_secs),
+            Duration::from_secs(server.transient_failure_cache_secs),
+            RetryPolicy {
+                connect_timeout: Duration::from_millis(server.maxmind_connect_timeout_ms),
+                timeout: Duration::from_millis(server.maxmind_timeout_ms),
+                retries: server.maxmind_retries,
+                backoff: Duration::from_millis(server.maxmind_retry_backoff_ms),
+            },
+            CircuitBreaker::new(
+                "maxmind",
+                server.circuit_breaker_failure_threshold,
+                Duration::from_secs(server.circuit_breaker_cooldown_secs),
+            ),
+        ))),
+        "mmdb" => {
+            let path = server
+                .mmdb_path
+                .as_deref()
+                .ok_or_else(|| "The mmdb resolver requires mmdb_path to be set".to_string())?;
+            Ok(Arc::new(MmdbResolver::open(path)?))
+        }
+        other => Err(format!("Unknown geo resolver: {}", other)),
+    }
+}
+
+/// Periodically checks the resolver backing files for changes and reloads them, also reloading
+/// unconditionally on SIGHUP. Records read from the local database are never cached, lookups
+/// see the new data as soon as it is swapped in.
+pub fn spawn_reloader(resolver: Arc<dyn GeoResolver>, check_interval: Option<Duration>) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => Some(hangup),
+            Err(e) => {
+                error!("Unable to listen for SIGHUP: {}", e);
+                None
+            }
+        };
+
+        loop {
+            let check = async {
+                match check_interval {
+                    Some(interval) => tokio::time::sleep(interval).await,
+                    None => futures::future::pending().await,
+                }
+            };
+
+            #[cfg(unix)]
+            let force = tokio::select! {
+                _ = check => false,
+                Some(_) = async {
+                    match hangup.as_mut() {
+                        Some(hangup) => hangup.recv().await,
+                        None => futures::future::pending().await,
+                    }
+                } => true,
+            };
+
+            #[cfg(not(unix))]
+            let force = {
+                check.await;
+                false
+            };
+
+            if force {
+                info!("SIGHUP received, reloading geo data");
+            }
+
+            if let Err(e) = resolver.reload(force).await {
+                error!("Geo data reload failed, keeping current data: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use ipnetwork::IpNetwork;
+
+use crate::geo::record::Traits;
+use crate::geo::{GeoError, GeoLookup, GeoRecord, GeoResolver, ServiceTier};
+
+/// Fixed geo record for a network, as found in the settings
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NetworkOverride {
+    /// Network in CIDR notation, `10.0.0.0/8` or `2001:db8::/32`
+    pub cidr: String,
+    /// Record sent for the network, in the GeoIP2 web service format
+    pub record: GeoRecord,
+    /// Custom labels such as `site = "HQ"`, without `,` or `=` in them
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Networks whose geo record is known in advance, such as office egress ranges that providers
+/// locate wrongly or private ranges they know nothing about
+#[derive(Debug, Default)]
+pub struct NetworkOverrides {
+    /// Sorted from the most specific network so that the first match is the longest prefix
+    entries: Vec<(IpNetwork, Arc<GeoRecord>)>,
+}
+
+impl NetworkOverrides {
+    pub fn new(overrides: &[NetworkOverride]) -> Result<Self, String> {
+        let mut entries = overrides
+            .iter()
+            .map(|o| {
+                let network = o
+                    .cidr
+                    .trim()
+                    .parse::<IpNetwork>()
+                    .map_err(|e| format!("Invalid network override {}: {}", o.cidr, e))?;
+                // Keep the network address only, `10.1.2.3/8` is read as `10.0.0.0/8`
+                let network = IpNetwork::new(network.network(), network.prefix())
+                    .map_err(|e| format!("Invalid network override {}: {}", o.cidr, e))?;
+
+                let mut record = o.record.clone();
+                record.labels.extend(o.labels.clone());
+                // Labels are sent as `name=value, name=value`, which these would make ambiguous
+                if let Some((name, value)) = record.labels.iter().find(|(name, value)| {
+                    name.is_empty() || name.contains([',', '=']) || value.contains([',', '='])
+                }) {
+                    return Err(format!(
+                        "Invalid label {} = {:?} in network override {}, names cannot be empty \
+                         and labels cannot contain ',' or '='",
+                        name, value, o.cidr
+                    ));
+                }
+                record
+                    .traits
+                    .get_or_insert_with(Traits::default)
+                    .network
+                    .get_or_insert_with(|| network.to_string());
+
+                Ok((network, Arc::new(record)))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        entries.sort_by_key(|(network, _)| std::cmp::Reverse(network.prefix()));
+        Ok(NetworkOverrides { entries })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Record of the most specific network containing `addr`
+    pub fn lookup(&self, addr: &IpAddr) -> Option<&Arc<GeoRecord>> {
+        self.entries
+            .iter()
+            .find(|(network, _)| network.contains(*addr))
+            .map(|(_, record)| record)
+    }
+
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        self.lookup(addr).is_some()
+    }
+}
+
+/// Answers from the network overrides, asking `inner` only for addresses they do not cover
+pub struct OverrideResolver {
+    overrides: Arc<NetworkOverrides>,
+    inner: Arc<dyn GeoResolver>,
+}
+
+impl OverrideResolver {
+    pub fn new(overrides: Arc<NetworkOverrides>, inner: Arc<dyn GeoResolver>) -> Self {
+        OverrideResolver { overrides, inner }
+    }
+}
+
+#[async_trait]
+impl GeoResolver for OverrideResolver {
+    fn name(&self) -> &'static str {
+        "override"
+    }
+
+    async fn lookup(&self, addr: &IpAddr, tier: ServiceTier) -> Result<GeoLookup, GeoError> {
+        match self.overrides.lookup(addr) {
+            Some(record) => Ok(GeoLookup {
+                record: record.clone(),
+                source: self.name(),
+                field_sources: Vec::new(),
+                cached: false,
+            }),
+            None => self.inner.lookup(addr, tier).await,
+        }
+    }
+
+    async fn reload(&self, force: bool) -> Result<bool, String> {
+        self.inner.reload(force).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NetworkOverride, NetworkOverrides, OverrideResolver};
+    use crate::geo::testing::FixedResolver;
+    use crate::geo::{GeoError, GeoResolver, ServiceTier};
+    use std::collections::BTreeMap;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    fn network_override(cidr: &str, city: &str, site: &str) -> NetworkOverride {
+        NetworkOverride {
+            cidr: cidr.to_string(),
+            record: serde_json::from_value(serde_json::json!({
+                "city": { "names": { "en": city } },
+                "country": { "iso_code": "CA" }
+            }))
+            .unwrap(),
+            labels: BTreeMap::from([("site".to_string(), site.to_string())]),
+        }
+    }
+
+    #[tokio::test]
+    async fn most_specific_network_wins() {
+        let overrides = Arc::new(
+            NetworkOverrides::new(&[
+                network_override("10.0.0.0/8", "Montreal", "DC"),
+                network_override("10.20.30.0/24", "Lavaltrie", "HQ"),
+            ])
+            .unwrap(),
+        );
+        let resolver = OverrideResolver::new(
+            overrides,
+            FixedResolver::failing("maxmind", GeoError::AddressNotFound),
+        );
+
+        let lookup = resolver
+            .lookup(&IpAddr::from_str("10.20.30.40").unwrap(), ServiceTier::City)
+            .await
+            .unwrap();
+        assert_eq!(lookup.source, "override");
+        assert_eq!(
+            lookup.record.city.as_ref().unwrap().name("en"),
+            Some("Lavaltrie")
+        );
+        assert_eq!(lookup.record.labels["site"], "HQ");
+        assert_eq!(
+            lookup.record.traits.as_ref().unwrap().network.as_deref(),
+            Some("10.20.30.0/24")
+        );
+
+        let lookup = resolver
+            .lookup(&IpAddr::from_str("10.1.1.1").unwrap(), ServiceTier::City)
+            .await
+            .unwrap();
+        assert_eq!(lookup.record.labels["site"], "DC");
+
+        assert_eq!(
+            resolver
+                .lookup(&IpAddr::from_str("192.168.1.1").unwrap(), ServiceTier::City)
+                .await
+                .unwrap_err(),
+            GeoError::AddressNotFound
+        );
+    }
+
+    #[test]
+    fn invalid_network() {
+        assert!(
+            NetworkOverrides::new(&[network_override("10.0.0.0/33", "Montreal", "DC")]).is_err()
+        );
+        assert!(NetworkOverrides::new(&[network_override("office", "Montreal", "DC")]).is_err());
+        assert!(
+            NetworkOverrides::new(&[network_override("10.0.0.0/8", "Montreal", "a,b")]).is_err()
+        );
+        assert!(
+            NetworkOverrides::new(&[network_override("10.0.0.0/8", "Montreal", "k=v")]).is_err()
+        );
+    }
+}
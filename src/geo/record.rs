@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+
+/// Localized names keyed by locale code (`en`, `fr`, `pt-BR`, ...)
+pub type Names = BTreeMap<String, String>;
+
+/// Locale used when a name is missing in the requested one
+pub const DEFAULT_LOCALE: &str = "en";
+
+fn localized<'a>(names: &'a Names, locale: &str) -> Option<&'a str> {
+    names
+        .get(locale)
+        .or_else(|| names.get(DEFAULT_LOCALE))
+        .map(String::as_str)
+}
+
+/// Geolocation data for an address, in the GeoIP2 model shared by the web services and the
+/// City/Enterprise databases. Fields a backend does not provide are left empty.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GeoRecord {
+    /// Ordered from the least to the most specific subdivision. Listed first so that records
+    /// can be written as TOML, where arrays cannot follow tables.
+    pub subdivisions: Vec<Subdivision>,
+    pub city: Option<City>,
+    pub continent: Option<Continent>,
+    pub country: Option<Country>,
+    pub registered_country: Option<Country>,
+    pub location: Option<Location>,
+    pub postal: Option<Postal>,
+    pub traits: Option<Traits>,
+    /// Custom labels given by the network overrides, providers never set them
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct City {
+    pub confidence: Option<u8>,
+    pub geoname_id: Option<u32>,
+    pub names: Names,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Continent {
+    pub code: Option<String>,
+    pub geoname_id: Option<u32>,
+    pub names: Names,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Country {
+    pub confidence: Option<u8>,
+    pub geoname_id: Option<u32>,
+    pub is_in_european_union: Option<bool>,
+    pub iso_code: Option<String>,
+    pub names: Names,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Location {
+    pub accuracy_radius: Option<u16>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub metro_code: Option<u16>,
+    pub time_zone: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Postal {
+    pub code: Option<String>,
+    pub confidence: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Subdivision {
+    pub confidence: Option<u8>,
+    pub geoname_id: Option<u32>,
+    pub iso_code: Option<String>,
+    pub names: Names,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Traits {
+    pub autonomous_system_number: Option<u32>,
+    pub autonomous_system_organization: Option<String>,
+    pub connection_type: Option<String>,
+    pub domain: Option<String>,
+    pub isp: Option<String>,
+    pub organization: Option<String>,
+    pub user_type: Option<String>,
+    pub network: Option<String>,
+}
+
+impl GeoRecord {
+    /// Fills the fields missing from this record with the ones of `other`
+    pub fn merge(&mut self, other: &GeoRecord) {
+        merge_part(&mut self.city, &other.city, City::merge);
+        merge_part(&mut self.continent, &other.continent, Continent::merge);
+        merge_part(&mut self.country, &other.country, Country::merge);
+        merge_part(
+            &mut self.registered_country,
+            &other.registered_country,
+            Country::merge,
+        );
+        merge_part(&mut self.location, &other.location, Location::merge);
+        merge_part(&mut self.postal, &other.postal, Postal::merge);
+        // Levels from different providers do not line up, the hierarchy is taken as a whole
+        if self.subdivisions.is_empty() {
+            self.subdivisions = other.subdivisions.clone();
+        }
+        merge_part(&mut self.traits, &other.traits, Traits::merge);
+        if self.labels.is_empty() {
+            self.labels.clone_from(&other.labels);
+        }
+    }
+
+    /// First level subdivision, the province or state
+    pub fn least_specific_subdivision(&self) -> Option<&Subdivision> {
+        self.subdivisions.first()
+    }
+
+    /// Deepest subdivision known for the address, the same as the least specific one when
+    /// the country has a single level
+    pub fn most_specific_subdivision(&self) -> Option<&Subdivision> {
+        self.subdivisions.last()
+    }
+
+    /// ISO 3166-2 code (`CA-QC`) of `subdivision`, which needs the country ISO code
+    pub fn iso_3166_2(&self, subdivision: &Subdivision) -> Option<String> {
+        let country = self.country.as_ref()?.iso_code.as_ref()?;
+        let subdivision = subdivision.iso_code.as_ref()?;
+        Some(format!("{}-{}", country, subdivision))
+    }
+}
+
+fn merge_part<T: Clone>(part: &mut Option<T>, other: &Option<T>, merge: fn(&mut T, &T)) {
+    match (part.as_mut(), other) {
+        (Some(part), Some(other)) => merge(part, other),
+        (None, Some(other)) => *part = Some(other.clone()),
+        _ => {}
+    }
+}
+
+fn fill<T: Clone>(value: &mut Option<T>, other: &Option<T>) {
+    if value.is_none() {
+        value.clone_from(other);
+    }
+}
+
+/// Names are taken as a whole so that every locale names the same place
+fn fill_names(names: &mut Names, other: &Names) {
+    if names.is_empty() {
+        names.clone_from(other);
+    }
+}
+
+impl City {
+    fn merge(&mut self, other: &City) {
+        fill(&mut self.confidence, &other.confidence);
+        fill(&mut self.geoname_id, &other.geoname_id);
+        fill_names(&mut self.names, &other.names);
+    }
+
+    pub fn name(&self, locale: &str) -> Option<&str> {
+        localized(&self.names, locale)
+    }
+}
+
+impl Continent {
+    fn merge(&mut self, other: &Continent) {
+        fill(&mut self.code, &other.code);
+        fill(&mut self.geoname_id, &other.geoname_id);
+        fill_names(&mut self.names, &other.names);
+    }
+
+    pub fn name(&self, locale: &str) -> Option<&str> {
+        localized(&self.names, locale)
+    }
+}
+
+impl Country {
+    fn merge(&mut self, other: &Country) {
+        fill(&mut self.confidence, &other.confidence);
+        fill(&mut self.geoname_id, &other.geoname_id);
+        fill(&mut self.is_in_european_union, &other.is_in_european_union);
+        fill(&mut self.iso_code, &other.iso_code);
+        fill_names(&mut self.names, &other.names);
+    }
+
+    pub fn name(&self, locale: &str) -> Option<&str> {
+        localized(&self.names, locale)
+    }
+}
+
+impl Location {
+    fn merge(&mut self, other: &Location) {
+        // Coordinates and their accuracy only make sense together, coordinates sent without an
+        // accuracy give way to ones that have it
+        let has_coordinates = |l: &Location| l.latitude.is_some() && l.longitude.is_some();
+        if !has_coordinates(self)
+            || (self.accuracy_radius.is_none()
+                && other.accuracy_radius.is_some()
+                && has_coordinates(other))
+        {
+            self.latitude = other.latitude;
+            self.longitude = other.longitude;
+            self.accuracy_radius = other.accuracy_radius;
+        }
+        fill(&mut self.metro_code, &other.metro_code);
+        fill(&mut self.time_zone, &other.time_zone);
+    }
+}
+
+impl Postal {
+    fn merge(&mut self, other: &Postal) {
+        if self.code.is_none() {
+            self.code.clone_from(&other.code);
+            self.confidence = other.confidence;
+        }
+    }
+}
+
+impl Traits {
+    fn merge(&mut self, other: &Traits) {
+        fill(
+            &mut self.autonomous_system_number,
+            &other.autonomous_system_number,
+        );
+        fill(
+            &mut self.autonomous_system_organization,
+            &other.autonomous_system_organization,
+        );
+        fill(&mut self.connection_type, &other.connection_type);
+        fill(&mut self.domain, &other.domain);
+        fill(&mut self.isp, &other.isp);
+        fill(&mut self.organization, &other.organization);
+        fill(&mut self.user_type, &other.user_type);
+        fill(&mut self.network, &other.network);
+    }
+}
+
+impl Subdivision {
+    pub fn name(&self, locale: &str) -> Option<&str> {
+        localized(&self.names, locale)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GeoRecord;
+
+    #[test]
+    fn deserialize_web_service_response() {
+        let record = serde_json::from_str::<GeoRecord>(
+            r#"{
+                "city": { "geoname_id": 6077243, "names": { "en": "Montreal", "fr": "Montréal" } },
+                "continent": { "code": "NA", "geoname_id": 6255149, "names": { "en": "North America" } },
+                "country": { "iso_code": "CA", "geoname_id": 6251999, "names": { "en": "Canada" } },
+                "location": {
+                    "accuracy_radius": 20,
+                    "latitude": 45.5063,
+                    "longitude": -73.5794,
+                    "time_zone": "America/Toronto"
+                },
+                "postal": { "code": "H3A" },
+                "registered_country": { "iso_code": "CA", "names": { "en": "Canada" } },
+                "subdivisions": [{ "iso_code": "QC", "geoname_id": 6115047, "names": { "en": "Quebec" } }],
+                "traits": {
+                    "autonomous_system_number": 5769,
+                    "isp": "Videotron",
+                    "ip_address": "24.200.0.1",
+                    "network": "24.200.0.0/14"
+                },
+                "maxmind": { "queries_remaining": 1000 }
+            }"#,
+        )
+        .unwrap();
+
+        let city = record.city.as_ref().unwrap();
+        assert_eq!(city.name("fr"), Some("Montréal"));
+        assert_eq!(city.name("de"), Some("Montreal"));
+        assert_eq!(record.subdivisions[0].iso_code.as_deref(), Some("QC"));
+        assert_eq!(record.location.unwrap().accuracy_radius, Some(20));
+        assert_eq!(
+            record.traits.unwrap().network.as_deref(),
+            Some("24.200.0.0/14")
+        );
+    }
+
+    #[test]
+    fn deserialize_sparse_response() {
+        let record = serde_json::from_str::<GeoRecord>(
+            r#"{ "country": { "iso_code": "FR" }, "traits": { "ip_address": "2.2.2.2" } }"#,
+        )
+        .unwrap();
+
+        assert!(record.city.is_none());
+        assert!(record.subdivisions.is_empty());
+        assert_eq!(record.country.unwrap().name("en"), None);
+    }
+
+    #[test]
+    fn subdivision_hierarchy() {
+        let record = serde_json::from_str::<GeoRecord>(
+            r#"{
+                "country": { "iso_code": "GB" },
+                "subdivisions": [
+                    { "iso_code": "ENG", "names": { "en": "England" } },
+                    { "iso_code": "WSM", "names": { "en": "Westminster" } }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let least = record.least_specific_subdivision().unwrap();
+        let most = record.most_specific_subdivision().unwrap();
+        assert_eq!(least.name("fr"), Some("England"));
+        assert_eq!(record.iso_3166_2(least).as_deref(), Some("GB-ENG"));
+        assert_eq!(record.iso_3166_2(most).as_deref(), Some("GB-WSM"));
+
+        let record = GeoRecord {
+            subdivisions: record.subdivisions.clone(),
+            ..Default::default()
+        };
+        assert_eq!(record.iso_3166_2(&record.subdivisions[0]), None);
+    }
+
+    #[test]
+    fn merge_fills_missing_fields() {
+        let mut record = serde_json::from_str::<GeoRecord>(
+            r#"{
+                "city": { "names": { "en": "Montreal" } },
+                "location": { "accuracy_radius": 100 },
+                "traits": { "network": "24.200.0.0/14" }
+            }"#,
+        )
+        .unwrap();
+        let other = serde_json::from_str::<GeoRecord>(
+            r#"{
+                "city": { "geoname_id": 6077243, "names": { "en": "Laval", "fr": "Laval" } },
+                "location": { "latitude": 45.5, "longitude": -73.58, "accuracy_radius": 5 },
+                "traits": { "isp": "Videotron", "network": "24.192.0.0/11" }
+            }"#,
+        )
+        .unwrap();
+
+        record.merge(&other);
+        let city = record.city.as_ref().unwrap();
+        assert_eq!(city.name("en"), Some("Montreal"));
+        assert_eq!(city.name("fr"), Some("Montreal"));
+        assert_eq!(city.geoname_id, Some(6077243));
+        assert_eq!(record.location.as_ref().unwrap().accuracy_radius, Some(5));
+        let traits = record.traits.as_ref().unwrap();
+        assert_eq!(traits.isp.as_deref(), Some("Videotron"));
+        assert_eq!(traits.network.as_deref(), Some("24.200.0.0/14"));
+
+        // Coordinates without an accuracy, as sent by CDNs
+        let mut record = serde_json::from_str::<GeoRecord>(
+            r#"{ "location": { "latitude": 45.51, "longitude": -73.59, "time_zone": "America/Toronto" } }"#,
+        )
+        .unwrap();
+        record.merge(&other);
+        let location = record.location.as_ref().unwrap();
+        assert_eq!(
+            (
+                location.latitude,
+                location.longitude,
+                location.accuracy_radius
+            ),
+            (Some(45.5), Some(-73.58), Some(5))
+        );
+        assert_eq!(location.time_zone.as_deref(), Some("America/Toronto"));
+    }
+}
@@ -0,0 +1,56 @@
+//! Test doubles shared by the geo and proxy tests
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde_json::Value;
+
+use crate::geo::{GeoError, GeoLookup, GeoRecord, GeoResolver, ServiceTier};
+
+/// Resolver giving the same answer for every address and counting the lookups it served
+pub(crate) struct FixedResolver {
+    name: &'static str,
+    answer: Result<Arc<GeoRecord>, GeoError>,
+    hits: AtomicUsize,
+}
+
+impl FixedResolver {
+    pub(crate) fn new(name: &'static str, record: Value) -> Arc<Self> {
+        Arc::new(FixedResolver {
+            name,
+            answer: Ok(Arc::new(serde_json::from_value(record).unwrap())),
+            hits: AtomicUsize::new(0),
+        })
+    }
+
+    pub(crate) fn failing(name: &'static str, error: GeoError) -> Arc<Self> {
+        Arc::new(FixedResolver {
+            name,
+            answer: Err(error),
+            hits: AtomicUsize::new(0),
+        })
+    }
+
+    pub(crate) fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl GeoResolver for FixedResolver {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn lookup(&self, _addr: &IpAddr, _tier: ServiceTier) -> Result<GeoLookup, GeoError> {
+        self.hits.fetch_add(1, Ordering::SeqCst);
+        Ok(GeoLookup {
+            record: self.answer.clone()?,
+            source: self.name,
+            field_sources: Vec::new(),
+            cached: false,
+        })
+    }
+}
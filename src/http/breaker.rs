@@ -0,0 +1,167 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use parking_lot::Mutex;
+
+use crate::metrics::METRICS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Calls go through, consecutive failures are counted
+    Closed,
+    /// Calls are refused until the cool-down elapses
+    Open,
+    /// A single trial call is let through to probe the provider
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BreakerState::Closed => write!(f, "closed"),
+            BreakerState::Open => write!(f, "open"),
+            BreakerState::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: BreakerState,
+    failures: u32,
+    changed_at: Instant,
+}
+
+/// Stops calling a failing provider for a cool-down period after repeated failures
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: &'static str,
+    failure_threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    /// A `failure_threshold` of 0 disables the breaker
+    pub fn new(name: &'static str, failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            name,
+            failure_threshold,
+            cooldown,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                failures: 0,
+                changed_at: Instant::now(),
+            }),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn state(&self) -> BreakerState {
+        self.inner.lock().state
+    }
+
+    /// Whether a call to the provider may be attempted now
+    pub fn allow(&self) -> bool {
+        if self.failure_threshold == 0 {
+            return true;
+        }
+
+        let mut inner = self.inner.lock();
+        match inner.state {
+            BreakerState::Closed => true,
+            // A trial that never reported back (cancelled request) must not wedge the breaker
+            BreakerState::Open | BreakerState::HalfOpen
+                if inner.changed_at.elapsed() >= self.cooldown =>
+            {
+                self.transition(&mut inner, BreakerState::HalfOpen);
+                true
+            }
+            BreakerState::Open | BreakerState::HalfOpen => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock();
+        inner.failures = 0;
+        if inner.state != BreakerState::Closed {
+            self.transition(&mut inner, BreakerState::Closed);
+        }
+    }
+
+    pub fn record_failure(&self) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock();
+        inner.failures = inner.failures.saturating_add(1);
+        match inner.state {
+            BreakerState::HalfOpen => self.transition(&mut inner, BreakerState::Open),
+            BreakerState::Closed if inner.failures >= self.failure_threshold => {
+                self.transition(&mut inner, BreakerState::Open)
+            }
+            _ => {}
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, state: BreakerState) {
+        match state {
+            BreakerState::Open => {
+                METRICS.record_breaker_opened();
+                warn!(
+                    "Circuit breaker for {} opened after {} consecutive failures, retrying in {:?}",
+                    self.name, inner.failures, self.cooldown
+                );
+            }
+            _ => info!("Circuit breaker for {} is now {}", self.name, state),
+        }
+
+        METRICS.set_breaker_state(state);
+        inner.state = state;
+        inner.changed_at = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BreakerState, CircuitBreaker};
+    use std::time::Duration;
+
+    #[test]
+    fn opens_after_threshold() {
+        let breaker = CircuitBreaker::new("test", 3, Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn half_open_trial() {
+        let breaker = CircuitBreaker::new("test", 1, Duration::ZERO);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn disabled_breaker() {
+        let breaker = CircuitBreaker::new("test", 0, Duration::from_secs(60));
+        for _ in 0..10 {
+            breaker.record_failure();
+        }
+        assert!(breaker.allow());
+    }
+}
@@ -1,17 +1,65 @@
+use crate::geo::{GeoError, GeoLookup, GeoRecord, GeoResolver, ServiceTier};
+use crate::http::breaker::CircuitBreaker;
+use crate::metrics::METRICS;
 use crate::priority_map::PriorityMap;
+use async_trait::async_trait;
 use base64::encode;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use hyper::client::HttpConnector;
 use hyper::header::AUTHORIZATION;
 use hyper::{Client, HeaderMap};
-use serde_json::Value;
+use hyper_tls::HttpsConnector;
+use log::debug;
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::net::IpAddr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::sync::RwLock;
 
 pub struct Inner {
-    pub client: Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>, hyper::Body>,
+    pub client: Client<HttpsConnector<HttpConnector>, hyper::Body>,
     pub headers: HeaderMap,
-    pub cache: RwLock<PriorityMap<String, Arc<Value>>>,
+    pub base_url: String,
+    pub api_version: String,
+    pub cache: RwLock<PriorityMap<LookupKey, Arc<GeoRecord>>>,
+    pub negative_cache: RwLock<PriorityMap<LookupKey, NegativeEntry>>,
+    pub negative_cache_duration: Duration,
+    pub transient_failure_cache_duration: Duration,
+    pub in_flight: Mutex<HashMap<LookupKey, PendingLookup>>,
+    pub retry: RetryPolicy,
+    pub breaker: CircuitBreaker,
+}
+
+/// Timeouts and retries applied to web service lookups
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub connect_timeout: Duration,
+    /// Upper bound for a whole lookup, retries included. Zero disables it.
+    pub timeout: Duration,
+    pub retries: u32,
+    /// Base delay before the first retry, doubled for each following one
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with jitter so concurrent lookups do not retry in lockstep
+    fn backoff_for(&self, attempt: u32) -> Duration {
+        let max = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let half = max / 2;
+        half + Duration::from_millis(fastrand::u64(..=half.as_millis() as u64))
+    }
+}
+
+type LookupKey = (ServiceTier, IpAddr);
+type PendingLookup = Shared<BoxFuture<'static, Result<Arc<GeoRecord>, GeoError>>>;
+
+#[derive(Debug)]
+pub struct NegativeEntry {
+    error: GeoError,
+    expires_at: SystemTime,
 }
 
 #[derive(Clone)]
@@ -20,7 +68,19 @@ pub struct HttpRequest {
 }
 
 impl HttpRequest {
-    pub fn new(id: &str, password: &str, cache_capacity: usize, cache_duration: Duration) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: &str,
+        password: &str,
+        base_url: &str,
+        api_version: &str,
+        cache_capacity: usize,
+        cache_duration: Duration,
+        negative_cache_duration: Duration,
+        transient_failure_cache_duration: Duration,
+        retry: RetryPolicy,
+        breaker: CircuitBreaker,
+    ) -> Self {
         let mut headers = HeaderMap::new();
         let encoded = encode(format!("{}:{}", id, password));
 
@@ -29,59 +89,443 @@ impl HttpRequest {
             format!("Basic {}", encoded).parse().expect("should be ok"),
         );
 
-        let connector = hyper_tls::HttpsConnector::new();
-        let client = Client::builder().build(connector);
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        if !retry.connect_timeout.is_zero() {
+            http.set_connect_timeout(Some(retry.connect_timeout));
+        }
+        let client = Client::builder().build(HttpsConnector::new_with_connector(http));
 
         HttpRequest {
             inner: Arc::new(Inner {
                 client,
                 headers,
+                base_url: base_url.trim_end_matches('/').to_string(),
+                api_version: api_version.trim_start_matches('v').to_string(),
                 cache: RwLock::new(PriorityMap::new(
                     cache_capacity,
                     cache_duration,
                     cache_duration,
                 )),
+                negative_cache: RwLock::new(PriorityMap::new(
+                    cache_capacity,
+                    negative_cache_duration.max(transient_failure_cache_duration),
+                    cache_duration,
+                )),
+                negative_cache_duration,
+                transient_failure_cache_duration,
+                in_flight: Mutex::new(HashMap::new()),
+                retry,
+                breaker,
             }),
         }
     }
+}
 
-    pub async fn lookup(&self, addr: &IpAddr) -> Result<Arc<Value>, ()> {
-        let addr_str = format!("{}", addr);
+impl Inner {
+    async fn cache_failure(&self, key: LookupKey, error: GeoError) {
+        let ttl = if error.is_permanent() {
+            self.negative_cache_duration
+        } else {
+            self.transient_failure_cache_duration
+        };
 
-        if !self.inner.cache.read().await.contains_key(&addr_str) {
-            let mut req = hyper::Request::builder()
-                .method(hyper::Method::GET)
-                .uri(format!(
-                    "https://geoip.maxmind.com/geoip/v2.1/city/{}",
-                    addr_str
-                ))
-                .body(hyper::Body::empty())
-                .map_err(|_| ())?;
+        if !ttl.is_zero() && error != GeoError::CircuitOpen {
+            self.negative_cache.write().await.insert(
+                key,
+                NegativeEntry {
+                    error,
+                    expires_at: SystemTime::now() + ttl,
+                },
+            );
+        }
+    }
 
-            req.headers_mut()
-                .extend(self.inner.headers.clone().into_iter());
+    async fn cached_failure(&self, key: &LookupKey) -> Option<GeoError> {
+        self.negative_cache
+            .read()
+            .await
+            .get(key)
+            .filter(|entry| entry.expires_at > SystemTime::now())
+            .map(|entry| entry.error.clone())
+    }
+}
 
-            let res = self.inner.client.request(req).await.map_err(|_| ())?;
+async fn fetch(
+    inner: &Inner,
+    tier: ServiceTier,
+    addr: &IpAddr,
+) -> Result<Arc<GeoRecord>, GeoError> {
+    let mut req = hyper::Request::builder()
+        .method(hyper::Method::GET)
+        .uri(format!(
+            "{}/geoip/v{}/{}/{}",
+            inner.base_url, inner.api_version, tier, addr
+        ))
+        .body(hyper::Body::empty())
+        .map_err(|e| GeoError::Transport(e.to_string()))?;
 
-            let bytes = hyper::body::to_bytes(res.into_body())
-                .await
-                .map_err(|_| ())?;
+    req.headers_mut().extend(inner.headers.clone());
+
+    let res = inner.client.request(req).await?;
+
+    let status = res.status();
+    let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+    if !status.is_success() {
+        return Err(GeoError::from_response(status, bytes.as_ref()));
+    }
+
+    let record = serde_json::from_slice::<GeoRecord>(bytes.as_ref())
+        .map_err(|e| GeoError::Json(e.to_string()))?;
+
+    Ok(Arc::new(record))
+}
+
+/// Runs `fetch` through the circuit breaker, retrying failures that may succeed on a new attempt
+async fn fetch_with_retries(
+    inner: &Inner,
+    tier: ServiceTier,
+    addr: &IpAddr,
+) -> Result<Arc<GeoRecord>, GeoError> {
+    if !inner.breaker.allow() {
+        return Err(GeoError::CircuitOpen);
+    }
+
+    let attempts = async {
+        let mut attempt = 0;
+        loop {
+            METRICS.record_provider_request();
+            match fetch(inner, tier, addr).await {
+                Err(e) if e.is_retryable() && attempt < inner.retry.retries => {
+                    attempt += 1;
+                    METRICS.record_provider_retry();
+                    debug!("Retrying lookup for {} after error: {}", addr, e);
+                    tokio::time::sleep(inner.retry.backoff_for(attempt)).await;
+                }
+                res => return res,
+            }
+        }
+    };
+
+    let res = if inner.retry.timeout.is_zero() {
+        attempts.await
+    } else {
+        tokio::time::timeout(inner.retry.timeout, attempts)
+            .await
+            .unwrap_or(Err(GeoError::Timeout))
+    };
+
+    match res {
+        // An unknown address still means the provider is answering
+        Err(ref e) if !e.is_permanent() => {
+            METRICS.record_provider_failure();
+            inner.breaker.record_failure();
+        }
+        _ => inner.breaker.record_success(),
+    }
+
+    res
+}
+
+#[async_trait]
+impl GeoResolver for HttpRequest {
+    fn name(&self) -> &'static str {
+        "maxmind"
+    }
+
+    async fn lookup(&self, addr: &IpAddr, tier: ServiceTier) -> Result<GeoLookup, GeoError> {
+        let key = (tier, *addr);
+
+        if let Some(record) = self.inner.cache.read().await.get(&key).cloned() {
+            return Ok(GeoLookup {
+                record,
+                source: self.name(),
+                field_sources: Vec::new(),
+                cached: true,
+            });
+        }
+
+        if let Some(error) = self.inner.cached_failure(&key).await {
+            return Err(error);
+        }
 
-            let json = serde_json::from_slice::<Value>(bytes.as_ref()).map_err(|_| ())?;
+        // Concurrent misses for the same key all wait on the first caller's query, which runs
+        // in its own task so that it completes and fills the cache even if every caller is gone
+        let pending = self
+            .inner
+            .in_flight
+            .lock()
+            .entry(key)
+            .or_insert_with(|| {
+                let inner = self.inner.clone();
+                tokio::spawn(async move {
+                    let res = fetch_with_retries(&inner, tier, &key.1).await;
+                    match res {
+                        Ok(ref record) => {
+                            inner.cache.write().await.insert(key, record.clone());
+                        }
+                        Err(ref error) => inner.cache_failure(key, error.clone()).await,
+                    }
+                    inner.in_flight.lock().remove(&key);
+                    res
+                })
+                .map(|joined| {
+                    joined.unwrap_or_else(|e| {
+                        Err(GeoError::Transport(format!("lookup task failed: {}", e)))
+                    })
+                })
+                .boxed()
+                .shared()
+            })
+            .clone();
 
-            self.inner
-                .cache
-                .write()
+        Ok(GeoLookup {
+            record: pending.await?,
+            source: self.name(),
+            field_sources: Vec::new(),
+            cached: false,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{HttpRequest, RetryPolicy};
+    use crate::geo::{GeoError, GeoResolver, ServiceTier};
+    use crate::http::breaker::{BreakerState, CircuitBreaker};
+    use futures::FutureExt;
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use hyper::{Body, Request, Response, StatusCode};
+    use std::convert::Infallible;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// Serves `handler` on a local port, returning the base url and the number of requests served
+    pub(crate) async fn spawn_mock_maxmind<F>(handler: F) -> (String, Arc<AtomicUsize>)
+    where
+        F: Fn(&Request<Body>) -> Response<Body> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let handler = Arc::new(handler);
+
+        let served = hits.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                let served = served.clone();
+                tokio::spawn(Http::new().serve_connection(
+                    stream,
+                    service_fn(move |req| {
+                        served.fetch_add(1, Ordering::SeqCst);
+                        let res = handler(&req);
+                        async move { Ok::<_, Infallible>(res) }
+                    }),
+                ));
+            }
+        });
+
+        (base_url, hits)
+    }
+
+    pub(crate) fn mock_resolver(base_url: &str) -> HttpRequest {
+        HttpRequest::new(
+            "id",
+            "password",
+            base_url,
+            "2.1",
+            16,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            Duration::ZERO,
+            RetryPolicy {
+                connect_timeout: Duration::from_secs(1),
+                timeout: Duration::from_secs(1),
+                retries: 1,
+                backoff: Duration::from_millis(1),
+            },
+            CircuitBreaker::new("maxmind", 2, Duration::from_secs(60)),
+        )
+    }
+
+    #[tokio::test]
+    async fn lookup_uses_configured_endpoint_and_tier() {
+        let (base_url, hits) = spawn_mock_maxmind(|req| {
+            Response::new(Body::from(format!(
+                r#"{{"traits": {{"domain": "{}"}}}}"#,
+                req.uri().path()
+            )))
+        })
+        .await;
+        let resolver = mock_resolver(&format!("{}/", base_url));
+        let ip = IpAddr::from_str("192.0.2.1").unwrap();
+
+        let lookup = resolver.lookup(&ip, ServiceTier::Country).await.unwrap();
+        assert_eq!(
+            lookup.record.traits.as_ref().unwrap().domain.as_deref(),
+            Some("/geoip/v2.1/country/192.0.2.1")
+        );
+        assert!(!lookup.cached);
+
+        let lookup = resolver.lookup(&ip, ServiceTier::Insights).await.unwrap();
+        assert_eq!(
+            lookup.record.traits.as_ref().unwrap().domain.as_deref(),
+            Some("/geoip/v2.1/insights/192.0.2.1")
+        );
+
+        // Each tier is cached on its own
+        assert!(
+            resolver
+                .lookup(&ip, ServiceTier::Country)
                 .await
-                .insert(addr_str.clone(), Arc::new(json));
+                .unwrap()
+                .cached
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn concurrent_lookups_are_coalesced() {
+        let (base_url, hits) =
+            spawn_mock_maxmind(|_| Response::new(Body::from(r#"{"city": {}}"#))).await;
+        let resolver = mock_resolver(&base_url);
+        let ip = IpAddr::from_str("192.0.2.1").unwrap();
+
+        let lookups =
+            futures::future::join_all((0..16).map(|_| resolver.lookup(&ip, ServiceTier::City)))
+                .await;
+
+        assert!(lookups.iter().all(|lookup| lookup.is_ok()));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert!(resolver.inner.in_flight.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn abandoned_lookups_complete() {
+        let (base_url, hits) =
+            spawn_mock_maxmind(|_| Response::new(Body::from(r#"{"city": {}}"#))).await;
+        let resolver = mock_resolver(&base_url);
+        let ip = IpAddr::from_str("192.0.2.1").unwrap();
+
+        // The only caller goes away, as when the client disconnects
+        assert!(resolver
+            .lookup(&ip, ServiceTier::City)
+            .now_or_never()
+            .is_none());
+
+        for _ in 0..100 {
+            if resolver.inner.in_flight.lock().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+        assert!(resolver.inner.in_flight.lock().is_empty());
+        assert!(
+            resolver
+                .lookup(&ip, ServiceTier::City)
+                .await
+                .unwrap()
+                .cached
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
 
-        self.inner
-            .cache
-            .read()
+    #[tokio::test]
+    async fn failures_are_negatively_cached() {
+        let (base_url, hits) = spawn_mock_maxmind(|req| {
+            let (status, code) = if req.uri().path().ends_with("192.0.2.1") {
+                (StatusCode::NOT_FOUND, "IP_ADDRESS_NOT_FOUND")
+            } else {
+                (StatusCode::SERVICE_UNAVAILABLE, "SERVER_ERROR")
+            };
+            let mut res = Response::new(Body::from(format!(
+                r#"{{"code": "{}", "error": "lookup failed"}}"#,
+                code
+            )));
+            *res.status_mut() = status;
+            res
+        })
+        .await;
+        let resolver = mock_resolver(&base_url);
+        let not_found = IpAddr::from_str("192.0.2.1").unwrap();
+        let unavailable = IpAddr::from_str("192.0.2.2").unwrap();
+
+        assert!(resolver
+            .lookup(&not_found, ServiceTier::City)
+            .await
+            .is_err());
+        assert!(resolver
+            .lookup(&not_found, ServiceTier::City)
+            .await
+            .is_err());
+        assert_eq!(
+            resolver
+                .inner
+                .cached_failure(&(ServiceTier::City, not_found))
+                .await,
+            Some(GeoError::AddressNotFound)
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // Transient failures are retried once and not cached with a zero ttl
+        assert!(resolver
+            .lookup(&unavailable, ServiceTier::City)
             .await
-            .get(&addr_str)
-            .cloned()
-            .ok_or(())
+            .is_err());
+        assert!(resolver
+            .lookup(&unavailable, ServiceTier::City)
+            .await
+            .is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn breaker_stops_calling_failing_provider() {
+        let (base_url, hits) = spawn_mock_maxmind(|_| {
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            res
+        })
+        .await;
+        let resolver = mock_resolver(&base_url);
+
+        for ip in ["192.0.2.1", "192.0.2.2"] {
+            let ip = IpAddr::from_str(ip).unwrap();
+            assert!(matches!(
+                resolver.lookup(&ip, ServiceTier::City).await,
+                Err(GeoError::Provider { status: 500, .. })
+            ));
+        }
+        assert_eq!(resolver.inner.breaker.state(), BreakerState::Open);
+
+        let ip = IpAddr::from_str("192.0.2.3").unwrap();
+        assert_eq!(
+            resolver.lookup(&ip, ServiceTier::City).await.unwrap_err(),
+            GeoError::CircuitOpen
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn slow_provider_times_out() {
+        let (base_url, _) = spawn_mock_maxmind(|_| {
+            std::thread::sleep(Duration::from_millis(1500));
+            Response::new(Body::from("{}"))
+        })
+        .await;
+        let resolver = mock_resolver(&base_url);
+        let ip = IpAddr::from_str("192.0.2.1").unwrap();
+
+        assert_eq!(
+            resolver.lookup(&ip, ServiceTier::City).await.unwrap_err(),
+            GeoError::Timeout
+        );
     }
 }
@@ -0,0 +1,6 @@
+//! Helpers for the upstreams of prux.
+//!
+//! [`signature::Signer::verify`] checks the signature prux adds to the geo headers, so that an
+//! upstream also reachable without prux can tell its enrichment from forged headers.
+
+pub mod signature;
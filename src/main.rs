@@ -5,6 +5,7 @@ use std::env;
 use std::io;
 use std::net;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use dns_lookup::lookup_host;
@@ -12,15 +13,26 @@ use env_logger::Builder;
 use hyper::server::conn::Http;
 use hyper::{Client, Uri};
 use hyper_tls::HttpsConnector;
-use log::LevelFilter;
+use log::{debug, error, warn, LevelFilter};
 use tokio::net::TcpListener;
+use tokio_native_tls::{native_tls, TlsConnector};
 
-use crate::http::request::HttpRequest;
+use crate::geo::overrides::NetworkOverrides;
+use crate::geo::ServiceTier;
+use crate::proxy::classify::IpClassifier;
+use crate::proxy::client_ip::ClientIpSources;
+use crate::proxy::headers::HeaderMapping;
+use crate::proxy::policy::GeoFailurePolicy;
+use crate::proxy::proxy_protocol::{
+    self, ProxiedAddrs, ProxyProtocolListener, ProxyProtocolVersion, UpstreamConnector,
+};
+use crate::proxy::utils::TrustedProxies;
 use crate::proxy::Proxy;
+use prux::signature::Signer;
 
-pub type IpResolver = HttpRequest;
-
+mod geo;
 mod http;
+mod metrics;
 mod priority_map;
 mod proxy;
 mod settings;
@@ -45,11 +57,131 @@ async fn main() -> io::Result<()> {
 
     builder.init();
 
-    let ip_resolver = HttpRequest::new(
-        &config.server.maxmind_id,
-        &config.server.maxmind_password,
-        config.server.cache_capacity,
-        Duration::from_secs(config.server.cache_duration_secs),
+    let maxmind_service = config
+        .server
+        .maxmind_service
+        .parse::<ServiceTier>()
+        .expect("Invalid maxmind service tier");
+
+    let failure_policy = Arc::new(
+        GeoFailurePolicy::new(
+            config
+                .server
+                .geo_failure_policy
+                .parse()
+                .expect("Invalid geo failure policy"),
+            config
+                .server
+                .geo_fail_open_path_inclusions
+                .as_deref()
+                .map(split_paths)
+                .unwrap_or_default(),
+            config
+                .server
+                .geo_fail_closed_path_inclusions
+                .as_deref()
+                .map(split_paths)
+                .unwrap_or_default(),
+            config.server.geo_failure_status,
+            &config.server.geo_failure_content_type,
+            config.server.geo_failure_body.clone(),
+        )
+        .expect("Invalid geo failure policy"),
+    );
+
+    let signer = config
+        .server
+        .signature_key
+        .as_deref()
+        .map(|key| Signer::new(key.as_bytes(), &config.server.signature_header))
+        .transpose()
+        .expect("Invalid geo header signature settings");
+
+    let header_mapping = Arc::new(
+        HeaderMapping::new(&config.server.geo_locale, &config.server.geo_headers)
+            .and_then(|mapping| {
+                mapping
+                    .with_encoding(config.server.geo_header_encoding.parse()?)
+                    .with_format(
+                        config.server.geo_header_format.parse()?,
+                        &config.server.geo_structured_header,
+                    )?
+                    .with_scrubbed_headers(
+                        &config
+                            .server
+                            .scrub_headers
+                            .as_deref()
+                            .map(split_paths)
+                            .unwrap_or_default(),
+                    )
+                    .map(|mapping| mapping.with_signer(signer))
+                    .and_then(|mapping| {
+                        mapping.with_sources_header(config.server.geo_sources_header.as_deref())
+                    })
+            })
+            .expect("Invalid geo header mapping"),
+    );
+
+    let wanted = header_mapping
+        .fields
+        .iter()
+        .map(|(field, _)| *field)
+        .collect::<Vec<_>>();
+    let network_overrides = Arc::new(
+        NetworkOverrides::new(&config.server.network_overrides).expect("Invalid network overrides"),
+    );
+    let ip_classifier = Arc::new(
+        IpClassifier::new(
+            &config
+                .server
+                .special_ip_inclusions
+                .as_deref()
+                .map(split_paths)
+                .unwrap_or_default(),
+        )
+        .expect("Invalid special ip inclusions"),
+    );
+    let client_ip_sources = Arc::new(
+        config
+            .server
+            .client_ip_headers
+            .as_deref()
+            .map(|headers| {
+                split_paths(headers)
+                    .iter()
+                    .map(|source| source.parse())
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .unwrap_or_else(|| {
+                ClientIpSources::from_header(
+                    config.server.forwarded_ip_header.as_deref(),
+                    config.server.use_forwarded_ip_header_only,
+                )
+            })
+            .and_then(|sources| {
+                ClientIpSources::new(
+                    sources,
+                    TrustedProxies::new(
+                        &config
+                            .server
+                            .trusted_proxies
+                            .as_deref()
+                            .map(split_paths)
+                            .unwrap_or_default(),
+                    )?,
+                    &config.server.cdn_ranges,
+                )
+            })
+            .expect("Invalid client ip sources"),
+    );
+    let ip_resolver =
+        geo::resolver_from_settings(&config.server, &wanted, network_overrides.clone())
+            .expect("Invalid geo resolver configuration");
+    geo::spawn_reloader(
+        ip_resolver.clone(),
+        Some(config.server.mmdb_reload_interval_secs)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
     );
 
     let server_uri = config
@@ -59,62 +191,155 @@ async fn main() -> io::Result<()> {
         .parse::<Uri>()
         .expect("Invalid upstream uri");
 
+    if let Some(metrics_port) = config.listener.metrics_port {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics_port).await {
+                error!("Unable to serve metrics: {}", e);
+            }
+        });
+    }
+
     let listener =
         TcpListener::bind((net::Ipv4Addr::new(0, 0, 0, 0), config.listener.port)).await?;
 
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
+    let proxy_protocol = Arc::new(
+        config
+            .listener
+            .proxy_protocol
+            .parse()
+            .and_then(|mode| {
+                ProxyProtocolListener::new(
+                    mode,
+                    &config
+                        .listener
+                        .proxy_protocol_sources
+                        .as_deref()
+                        .map(split_paths)
+                        .unwrap_or_default(),
+                )
+            })
+            .expect("Invalid proxy protocol settings"),
+    );
+    let upstream_proxy_protocol = config
+        .server
+        .upstream_proxy_protocol
+        .as_deref()
+        .map(str::parse::<ProxyProtocolVersion>)
+        .transpose()
+        .expect("Invalid upstream proxy protocol version");
+
+    let tls = TlsConnector::from(
+        native_tls::TlsConnector::new().expect("Unable to initialize the TLS connector"),
+    );
+    let client = Client::builder().build::<_, hyper::Body>(HttpsConnector::from((
+        UpstreamConnector::new(None),
+        tls.clone(),
+    )));
 
     let http = Http::new();
 
-    while let Ok((stream, addr)) = listener.accept().await {
-        let client_hpr = client.clone();
+    while let Ok((mut stream, addr)) = listener.accept().await {
         let server_uri = server_uri.clone();
         let resolver = ip_resolver.clone();
-        let source = addr.ip();
+        let proxy_protocol = proxy_protocol.clone();
+        let client = client.clone();
+        let tls = tls.clone();
+        let http = http.clone();
 
-        let ip_inclusions = config
-            .server
-            .ip_path_inclusions
-            .split(',')
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
-        let maxmind_inclusions = config
+        let ip_inclusions = split_paths(&config.server.ip_path_inclusions);
+        let maxmind_inclusions = split_paths(&config.server.maxmind_path_inclusions);
+        let maxmind_tier_inclusions = [
+            (
+                ServiceTier::Insights,
+                &config.server.maxmind_insights_path_inclusions,
+            ),
+            (
+                ServiceTier::City,
+                &config.server.maxmind_city_path_inclusions,
+            ),
+            (
+                ServiceTier::Country,
+                &config.server.maxmind_country_path_inclusions,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(tier, paths)| paths.as_deref().map(|p| (tier, split_paths(p))))
+        .collect::<Vec<_>>();
+        let exclusions = config
             .server
-            .maxmind_path_inclusions
-            .split(',')
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
-        let exclusions = if let Some(exclusion_string) = config.server.path_exclusions.clone() {
-            exclusion_string
-                .split(',')
-                .map(|s| s.to_string())
-                .collect::<Vec<String>>()
-        } else {
-            Vec::new()
-        };
+            .path_exclusions
+            .as_deref()
+            .map(split_paths)
+            .unwrap_or_default();
+        let client_ip_sources = client_ip_sources.clone();
+        let use_forwarded_ip_header_only = config.server.use_forwarded_ip_header_only;
+        let failure_policy = failure_policy.clone();
+        let header_mapping = header_mapping.clone();
+        let network_overrides = network_overrides.clone();
+        let ip_classifier = ip_classifier.clone();
+        let send_client_port = config.server.send_client_port;
+        let cdn_geo_headers = config.server.cdn_geo_headers;
+
+        tokio::spawn(async move {
+            // Behind an L4 load balancer the peer is the balancer, the client is in the header
+            let proxied = match proxy_protocol.accept(&mut stream, addr).await {
+                Ok(proxied) => proxied,
+                Err(e) => {
+                    warn!("Dropping connection from {}: {}", addr, e);
+                    return;
+                }
+            };
+            let source = proxied.map_or(addr, |proxied| proxied.source);
+
+            // Upstream connections carry the client of this connection, they cannot be shared
+            let client = match (upstream_proxy_protocol, stream.local_addr()) {
+                (Some(version), Ok(local)) => {
+                    let addrs = proxied.unwrap_or(ProxiedAddrs {
+                        source,
+                        destination: local,
+                    });
+                    Client::builder().build::<_, hyper::Body>(HttpsConnector::from((
+                        UpstreamConnector::new(Some(proxy_protocol::encode(version, &addrs))),
+                        tls,
+                    )))
+                }
+                _ => client,
+            };
 
-        let http_proxy = http.serve_connection(
-            stream,
-            Proxy::new(
+            let proxy = Proxy::new(
                 server_uri,
-                Some(source),
+                Some(source.ip()),
+                Some(source.port()),
                 resolver,
-                client_hpr,
+                client,
                 ip_inclusions,
                 maxmind_inclusions,
+                maxmind_tier_inclusions,
+                maxmind_service,
                 Some(exclusions),
-                config.server.forwarded_ip_header.clone(),
-                config.server.use_forwarded_ip_header_only,
-            ),
-        );
+                client_ip_sources,
+                use_forwarded_ip_header_only,
+                failure_policy,
+                header_mapping,
+                network_overrides,
+                ip_classifier,
+                send_client_port,
+                cdn_geo_headers,
+            );
 
-        tokio::spawn(http_proxy);
+            if let Err(e) = http.serve_connection(stream, proxy).await {
+                debug!("Connection from {} failed: {}", addr, e);
+            }
+        });
     }
 
     Ok(())
 }
 
+fn split_paths(paths: &str) -> Vec<String> {
+    paths.split(',').map(|s| s.to_string()).collect()
+}
+
 pub fn sockaddr_from_uri(uri: &str) -> Result<SocketAddr, String> {
     let uri: Uri = uri
         .parse()
@@ -0,0 +1,149 @@
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::io;
+use std::net;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Response, StatusCode};
+use log::info;
+use tokio::net::TcpListener;
+
+use crate::http::breaker::BreakerState;
+
+/// Process wide counters exposed in the Prometheus text format
+pub struct Metrics {
+    provider_requests: AtomicU64,
+    provider_failures: AtomicU64,
+    provider_retries: AtomicU64,
+    breaker_opened: AtomicU64,
+    breaker_state: AtomicU8,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            provider_requests: AtomicU64::new(0),
+            provider_failures: AtomicU64::new(0),
+            provider_retries: AtomicU64::new(0),
+            breaker_opened: AtomicU64::new(0),
+            breaker_state: AtomicU8::new(0),
+        }
+    }
+
+    pub fn record_provider_request(&self) {
+        self.provider_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_provider_failure(&self) {
+        self.provider_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_provider_retry(&self) {
+        self.provider_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_breaker_opened(&self) {
+        self.breaker_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_breaker_state(&self, state: BreakerState) {
+        let value = match state {
+            BreakerState::Closed => 0,
+            BreakerState::Open => 1,
+            BreakerState::HalfOpen => 2,
+        };
+        self.breaker_state.store(value, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "prux_geo_provider_requests_total",
+                "Requests sent to the geo web service",
+                &self.provider_requests,
+            ),
+            (
+                "prux_geo_provider_failures_total",
+                "Failed requests to the geo web service",
+                &self.provider_failures,
+            ),
+            (
+                "prux_geo_provider_retries_total",
+                "Retried requests to the geo web service",
+                &self.provider_retries,
+            ),
+            (
+                "prux_geo_circuit_breaker_opened_total",
+                "Times the geo web service circuit breaker opened",
+                &self.breaker_opened,
+            ),
+        ];
+
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP prux_geo_circuit_breaker_state Geo web service circuit breaker state (0 closed, 1 open, 2 half-open)"
+        );
+        let _ = writeln!(out, "# TYPE prux_geo_circuit_breaker_state gauge");
+        let _ = writeln!(
+            out,
+            "prux_geo_circuit_breaker_state {}",
+            self.breaker_state.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
+
+/// Serves the metrics on `port` for any request path
+pub async fn serve(port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind((net::Ipv4Addr::new(0, 0, 0, 0), port)).await?;
+    info!("Serving metrics on port {}", port);
+
+    let http = Http::new();
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(http.serve_connection(
+            stream,
+            service_fn(|_req| async {
+                let mut response = Response::new(Body::from(METRICS.render()));
+                *response.status_mut() = StatusCode::OK;
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; version=0.0.4"),
+                );
+                Ok::<_, Infallible>(response)
+            }),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use crate::http::breaker::BreakerState;
+
+    #[test]
+    fn render_breaker_state() {
+        let metrics = Metrics::new();
+        metrics.record_breaker_opened();
+        metrics.set_breaker_state(BreakerState::Open);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("prux_geo_circuit_breaker_opened_total 1\n"));
+        assert!(rendered.contains("prux_geo_circuit_breaker_state 1\n"));
+        assert!(rendered.contains("prux_geo_provider_requests_total 0\n"));
+    }
+}
@@ -0,0 +1,241 @@
+//! Classification of client addresses following the IANA IPv4 and IPv6 special-purpose address
+//! registries, so that only addresses a geo provider can know about are looked up.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use ipnetwork::IpNetwork;
+
+/// IPv4 ranges that are not globally reachable, with the registry name of each of them
+const SPECIAL_PURPOSE_V4: &[(Ipv4Addr, u8, &str)] = &[
+    (Ipv4Addr::new(0, 0, 0, 0), 8, "this network"),
+    (Ipv4Addr::new(10, 0, 0, 0), 8, "private use"),
+    (Ipv4Addr::new(100, 64, 0, 0), 10, "shared address space"),
+    (Ipv4Addr::new(127, 0, 0, 0), 8, "loopback"),
+    (Ipv4Addr::new(169, 254, 0, 0), 16, "link local"),
+    (Ipv4Addr::new(172, 16, 0, 0), 12, "private use"),
+    (Ipv4Addr::new(192, 0, 0, 0), 24, "IETF protocol assignments"),
+    (Ipv4Addr::new(192, 0, 2, 0), 24, "documentation"),
+    (Ipv4Addr::new(192, 88, 99, 0), 24, "6to4 relay anycast"),
+    (Ipv4Addr::new(192, 168, 0, 0), 16, "private use"),
+    (Ipv4Addr::new(198, 18, 0, 0), 15, "benchmarking"),
+    (Ipv4Addr::new(198, 51, 100, 0), 24, "documentation"),
+    (Ipv4Addr::new(203, 0, 113, 0), 24, "documentation"),
+    (Ipv4Addr::new(224, 0, 0, 0), 4, "multicast"),
+    (Ipv4Addr::new(240, 0, 0, 0), 4, "reserved"),
+];
+
+/// Globally reachable assignments inside the IPv4 special-purpose ranges
+const GLOBAL_V4: &[(Ipv4Addr, u8)] = &[
+    // Port Control Protocol and TURN anycast
+    (Ipv4Addr::new(192, 0, 0, 9), 32),
+    (Ipv4Addr::new(192, 0, 0, 10), 32),
+];
+
+/// IPv6 ranges that are not globally reachable, with the registry name of each of them.
+/// IPv4-mapped addresses are not listed, they are classified as the IPv4 address they carry.
+const SPECIAL_PURPOSE_V6: &[(Ipv6Addr, u8, &str)] = &[
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 128, "unspecified"),
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 128, "loopback"),
+    (Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0), 96, "NAT64"),
+    (
+        Ipv6Addr::new(0x64, 0xff9b, 1, 0, 0, 0, 0, 0),
+        48,
+        "local-use NAT64",
+    ),
+    (
+        Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, 0),
+        64,
+        "discard-only",
+    ),
+    (
+        Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 0),
+        23,
+        "IETF protocol assignments",
+    ),
+    (
+        Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0),
+        32,
+        "documentation",
+    ),
+    (
+        Ipv6Addr::new(0x3fff, 0, 0, 0, 0, 0, 0, 0),
+        20,
+        "documentation",
+    ),
+    (
+        Ipv6Addr::new(0x5f00, 0, 0, 0, 0, 0, 0, 0),
+        16,
+        "segment routing SIDs",
+    ),
+    (
+        Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0),
+        7,
+        "unique local",
+    ),
+    (Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10, "link local"),
+    (Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0), 10, "site local"),
+    (Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0), 8, "multicast"),
+];
+
+/// Globally reachable assignments inside the IPv6 special-purpose ranges
+const GLOBAL_V6: &[(Ipv6Addr, u8)] = &[
+    (Ipv6Addr::new(0x2001, 1, 0, 0, 0, 0, 0, 1), 128),
+    (Ipv6Addr::new(0x2001, 1, 0, 0, 0, 0, 0, 2), 128),
+    (Ipv6Addr::new(0x2001, 3, 0, 0, 0, 0, 0, 0), 32),
+    (Ipv6Addr::new(0x2001, 4, 0x112, 0, 0, 0, 0, 0), 48),
+    (Ipv6Addr::new(0x2001, 0x20, 0, 0, 0, 0, 0, 0), 28),
+    (Ipv6Addr::new(0x2001, 0x30, 0, 0, 0, 0, 0, 0), 28),
+];
+
+fn v4_contains(network: Ipv4Addr, prefix: u8, ip: Ipv4Addr) -> bool {
+    let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+    u32::from(network) & mask == u32::from(ip) & mask
+}
+
+fn v6_contains(network: Ipv6Addr, prefix: u8, ip: Ipv6Addr) -> bool {
+    let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+    u128::from(network) & mask == u128::from(ip) & mask
+}
+
+/// Registry name of the special-purpose range `ip` belongs to, `None` for globally reachable
+/// addresses
+pub fn special_purpose(ip: &IpAddr) -> Option<&'static str> {
+    match canonical_ip(*ip) {
+        IpAddr::V4(ip) => {
+            if GLOBAL_V4
+                .iter()
+                .any(|(network, prefix)| v4_contains(*network, *prefix, ip))
+            {
+                return None;
+            }
+            SPECIAL_PURPOSE_V4
+                .iter()
+                .find(|(network, prefix, _)| v4_contains(*network, *prefix, ip))
+                .map(|(_, _, name)| *name)
+        }
+        IpAddr::V6(ip) => {
+            if GLOBAL_V6
+                .iter()
+                .any(|(network, prefix)| v6_contains(*network, *prefix, ip))
+            {
+                return None;
+            }
+            SPECIAL_PURPOSE_V6
+                .iter()
+                .find(|(network, prefix, _)| v6_contains(*network, *prefix, ip))
+                .map(|(_, _, name)| *name)
+        }
+    }
+}
+
+/// IPv4 address carried by an IPv4-mapped IPv6 address, `::ffff:a.b.c.d`, as sent by dual-stack
+/// sockets and some proxies. Other addresses are returned as is.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Decides which client addresses are worth a geo lookup
+#[derive(Debug, Default)]
+pub struct IpClassifier {
+    /// Special-purpose ranges the operator wants treated as global, such as a carrier-grade NAT
+    /// range whose addresses the geo provider knows about
+    inclusions: Vec<IpNetwork>,
+}
+
+impl IpClassifier {
+    pub fn new(inclusions: &[String]) -> Result<Self, String> {
+        Ok(IpClassifier {
+            inclusions: inclusions
+                .iter()
+                .map(|cidr| {
+                    IpNetwork::from_str(cidr.trim())
+                        .map_err(|e| format!("Invalid special ip inclusion {}: {}", cidr, e))
+                })
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn is_global(&self, ip: &IpAddr) -> bool {
+        let ip = canonical_ip(*ip);
+        special_purpose(&ip).is_none() || self.inclusions.iter().any(|network| network.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{canonical_ip, special_purpose, IpClassifier};
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn special_purpose_ranges() {
+        for (addr, name) in [
+            ("0.1.2.3", "this network"),
+            ("10.10.10.10", "private use"),
+            ("100.64.0.1", "shared address space"),
+            ("100.127.255.255", "shared address space"),
+            ("127.0.0.1", "loopback"),
+            ("169.254.169.254", "link local"),
+            ("172.31.0.1", "private use"),
+            ("192.0.0.8", "IETF protocol assignments"),
+            ("192.0.2.1", "documentation"),
+            ("198.19.255.1", "benchmarking"),
+            ("203.0.113.7", "documentation"),
+            ("239.255.255.250", "multicast"),
+            ("250.1.1.1", "reserved"),
+            ("255.255.255.255", "reserved"),
+            ("::", "unspecified"),
+            ("::1", "loopback"),
+            ("64:ff9b::808:808", "NAT64"),
+            ("64:ff9b:1::1", "local-use NAT64"),
+            ("2001::1", "IETF protocol assignments"),
+            ("2001:db8::1", "documentation"),
+            ("fd00::1", "unique local"),
+            ("fe80::1", "link local"),
+            ("ff02::1", "multicast"),
+            ("::ffff:192.168.1.1", "private use"),
+        ] {
+            assert_eq!(special_purpose(&ip(addr)), Some(name), "{}", addr);
+        }
+
+        for addr in [
+            "8.8.8.8",
+            "100.63.255.255",
+            "100.128.0.1",
+            "192.0.0.9",
+            "198.20.0.1",
+            "2001:4860:4860::8888",
+            "2001:20::1",
+            "2606:4700::1111",
+            "::ffff:24.201.0.1",
+        ] {
+            assert_eq!(special_purpose(&ip(addr)), None, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        assert_eq!(canonical_ip(ip("::ffff:24.201.0.1")), ip("24.201.0.1"));
+        assert_eq!(canonical_ip(ip("2001:db8::1")), ip("2001:db8::1"));
+        assert_eq!(canonical_ip(ip("24.201.0.1")), ip("24.201.0.1"));
+    }
+
+    #[test]
+    fn inclusions() {
+        let classifier = IpClassifier::new(&["100.64.0.0/10".to_string()]).unwrap();
+        assert!(classifier.is_global(&ip("100.64.1.1")));
+        assert!(classifier.is_global(&ip("::ffff:100.64.1.1")));
+        assert!(!classifier.is_global(&ip("10.0.0.1")));
+        assert!(!IpClassifier::default().is_global(&ip("100.64.1.1")));
+
+        assert!(IpClassifier::new(&["100.64.0.0/40".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+//! Ordered sources of the client address: headers set by CDN edges, single address headers such
+//! as `X-Real-IP`, and the X-Forwarded-For and Forwarded hop lists.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use hyper::header::HeaderName;
+use hyper::HeaderMap;
+
+use crate::proxy::utils::{
+    client_hop, get_ips_from_forwarded_header, get_ips_from_x_forwarded_header, parse_ip,
+    parse_ip_port, ClientAddr, TrustedProxies,
+};
+
+/// CDN whose edges send the client address in a header of their own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CdnPreset {
+    Cloudflare,
+    Akamai,
+    Fastly,
+    CloudFront,
+}
+
+impl CdnPreset {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CdnPreset::Cloudflare => "cloudflare",
+            CdnPreset::Akamai => "akamai",
+            CdnPreset::Fastly => "fastly",
+            CdnPreset::CloudFront => "cloudfront",
+        }
+    }
+
+    /// Header carrying the client address
+    pub fn header(&self) -> &'static str {
+        match self {
+            CdnPreset::Cloudflare => "CF-Connecting-IP",
+            CdnPreset::Akamai => "True-Client-IP",
+            CdnPreset::Fastly => "Fastly-Client-IP",
+            CdnPreset::CloudFront => "CloudFront-Viewer-Address",
+        }
+    }
+
+    fn parse(&self, value: &str) -> Option<ClientAddr> {
+        match self {
+            // Always `address:port`, IPv6 addresses being sent without brackets
+            CdnPreset::CloudFront => {
+                let (ip, port) = value.trim().rsplit_once(':')?;
+                Some((parse_ip(ip)?, Some(port.parse().ok()?)))
+            }
+            _ => parse_ip_port(value),
+        }
+    }
+}
+
+impl fmt::Display for CdnPreset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for CdnPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "cloudflare" => Ok(CdnPreset::Cloudflare),
+            "akamai" => Ok(CdnPreset::Akamai),
+            "fastly" => Ok(CdnPreset::Fastly),
+            "cloudfront" => Ok(CdnPreset::CloudFront),
+            other => Err(format!("Unknown CDN preset: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientIpSource {
+    /// Header of a CDN, only read when the peer is one of its edges
+    Cdn(CdnPreset),
+    /// Header holding a single address, such as `X-Real-IP`
+    Header(HeaderName),
+    XForwardedFor,
+    Forwarded,
+}
+
+impl FromStr for ClientIpSource {
+    type Err = String;
+
+    /// Parses a CDN preset name, `X-Forwarded-For`, `Forwarded` or any other header name
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(cdn) = CdnPreset::from_str(s) {
+            return Ok(ClientIpSource::Cdn(cdn));
+        }
+
+        match s.to_lowercase().as_str() {
+            "x-forwarded-for" => Ok(ClientIpSource::XForwardedFor),
+            "forwarded" => Ok(ClientIpSource::Forwarded),
+            _ => HeaderName::from_str(s)
+                .map(ClientIpSource::Header)
+                .map_err(|e| format!("Invalid client ip header {}: {}", s, e)),
+        }
+    }
+}
+
+/// Where the client address of a request is read from
+#[derive(Debug, Default)]
+pub struct ClientIpSources {
+    /// Sources by priority, the first one present in a request being used
+    sources: Vec<ClientIpSource>,
+    /// Proxies allowed to send the non CDN sources, any peer when empty
+    trusted_proxies: TrustedProxies,
+    cdn_edges: Vec<(CdnPreset, TrustedProxies)>,
+}
+
+impl ClientIpSources {
+    /// `cdn_ranges` maps CDN preset names to comma separated files listing the networks of
+    /// their edges, one per line
+    pub fn new(
+        sources: Vec<ClientIpSource>,
+        trusted_proxies: TrustedProxies,
+        cdn_ranges: &HashMap<String, String>,
+    ) -> Result<Self, String> {
+        let cdn_edges = cdn_ranges
+            .iter()
+            .map(|(cdn, paths)| {
+                let cdn = CdnPreset::from_str(cdn)?;
+                Ok((cdn, load_edges(cdn, paths)?))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        if let Some(cdn) = sources.iter().find_map(|source| match source {
+            ClientIpSource::Cdn(cdn) if !cdn_edges.iter().any(|(c, _)| c == cdn) => Some(cdn),
+            _ => None,
+        }) {
+            return Err(format!(
+                "The {} client ip source requires cdn_ranges.{} to be set",
+                cdn, cdn
+            ));
+        }
+
+        Ok(ClientIpSources {
+            sources,
+            trusted_proxies,
+            cdn_edges,
+        })
+    }
+
+    /// Sources of the single header settings: `header`, then X-Forwarded-For and Forwarded
+    /// unless `header_only` is set
+    pub fn from_header(
+        header: Option<&str>,
+        header_only: bool,
+    ) -> Result<Vec<ClientIpSource>, String> {
+        let mut sources = header
+            .map(|header| {
+                HeaderName::from_str(header.trim())
+                    .map(ClientIpSource::Header)
+                    .map_err(|e| format!("Invalid forwarded ip header {}: {}", header, e))
+            })
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if !header_only {
+            sources.extend([ClientIpSource::XForwardedFor, ClientIpSource::Forwarded]);
+        }
+        Ok(sources)
+    }
+
+    /// CDN whose edge is `peer`
+    pub fn cdn(&self, peer: Option<IpAddr>) -> Option<CdnPreset> {
+        let peer = peer?;
+        self.cdn_edges
+            .iter()
+            .find(|(_, edges)| edges.contains(&peer))
+            .map(|(cdn, _)| *cdn)
+    }
+
+    /// Client address of a request received from `peer`. The first source present in `headers`
+    /// and trusted from `peer` decides, even when its value cannot be read.
+    pub fn client_addr(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<ClientAddr> {
+        let peer_is_proxy = self.trusted_proxies.is_empty()
+            || peer.is_some_and(|ip| self.trusted_proxies.contains(&ip));
+        let cdn = self.cdn(peer);
+
+        self.sources
+            .iter()
+            .find_map(|source| match source {
+                ClientIpSource::Cdn(preset) if cdn == Some(*preset) => headers
+                    .get(preset.header())
+                    .map(|value| preset.parse(&String::from_utf8_lossy(value.as_bytes()))),
+                ClientIpSource::Header(name) if peer_is_proxy => headers
+                    .get(name)
+                    .map(|value| parse_ip_port(&String::from_utf8_lossy(value.as_bytes()))),
+                ClientIpSource::XForwardedFor if peer_is_proxy => {
+                    get_ips_from_x_forwarded_header(headers)
+                        .map(|hops| client_hop(&hops, &self.trusted_proxies))
+                }
+                ClientIpSource::Forwarded if peer_is_proxy => {
+                    get_ips_from_forwarded_header(headers)
+                        .map(|hops| client_hop(&hops, &self.trusted_proxies))
+                }
+                _ => None,
+            })
+            .flatten()
+    }
+}
+
+fn load_edges(cdn: CdnPreset, paths: &str) -> Result<TrustedProxies, String> {
+    let mut networks = Vec::new();
+    for path in paths.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {} edge ranges {}: {}", cdn, path, e))?;
+        networks.extend(
+            content
+                .lines()
+                .map(|line| line.split('#').next().unwrap_or_default().trim())
+                .filter(|line| !line.is_empty())
+                .map(str::to_string),
+        );
+    }
+
+    if networks.is_empty() {
+        return Err(format!("No {} edge ranges found in {}", cdn, paths));
+    }
+    TrustedProxies::new(&networks)
+        .map_err(|e| format!("Invalid {} edge ranges in {}: {}", cdn, paths, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CdnPreset, ClientIpSource, ClientIpSources};
+    use crate::proxy::utils::TrustedProxies;
+    use hyper::header::HeaderValue;
+    use hyper::HeaderMap;
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        IpAddr::from_str(s).ok()
+    }
+
+    #[test]
+    fn source_names() {
+        assert_eq!(
+            ClientIpSource::from_str("Cloudflare"),
+            Ok(ClientIpSource::Cdn(CdnPreset::Cloudflare))
+        );
+        assert_eq!(
+            ClientIpSource::from_str(" X-Forwarded-For"),
+            Ok(ClientIpSource::XForwardedFor)
+        );
+        assert_eq!(
+            ClientIpSource::from_str("X-Real-IP"),
+            Ok(ClientIpSource::Header("x-real-ip".parse().unwrap()))
+        );
+        assert!(ClientIpSource::from_str("X Real IP").is_err());
+    }
+
+    #[test]
+    fn cdn_headers_trusted_from_edges() {
+        let dir = std::env::temp_dir().join(format!("prux-cdn-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let v4 = dir.join("cloudflare-v4.txt");
+        let v6 = dir.join("cloudflare-v6.txt");
+        std::fs::write(&v4, "# Cloudflare\n173.245.48.0/20\n\n103.21.244.0/22\n").unwrap();
+        std::fs::write(&v6, "2400:cb00::/32\n").unwrap();
+        let cloudfront = dir.join("cloudfront.txt");
+        std::fs::write(&cloudfront, "130.176.0.0/16\n").unwrap();
+
+        let cdn_ranges = HashMap::from([
+            (
+                "cloudflare".to_string(),
+                format!("{}, {}", v4.display(), v6.display()),
+            ),
+            ("cloudfront".to_string(), cloudfront.display().to_string()),
+        ]);
+        let sources = ClientIpSources::new(
+            vec![
+                ClientIpSource::Cdn(CdnPreset::Cloudflare),
+                ClientIpSource::Cdn(CdnPreset::CloudFront),
+                ClientIpSource::from_str("X-Real-IP").unwrap(),
+                ClientIpSource::XForwardedFor,
+            ],
+            TrustedProxies::default(),
+            &cdn_ranges,
+        )
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("CF-Connecting-IP", HeaderValue::from_static("198.51.100.7"));
+        headers.insert("X-Real-IP", HeaderValue::from_static("203.0.113.9"));
+        headers.insert(
+            "CloudFront-Viewer-Address",
+            HeaderValue::from_static("2001:db8::7:46532"),
+        );
+
+        assert_eq!(sources.cdn(ip("173.245.48.1")), Some(CdnPreset::Cloudflare));
+        assert_eq!(
+            sources.client_addr(&headers, ip("2400:cb00::1")),
+            Some((ip("198.51.100.7").unwrap(), None))
+        );
+        assert_eq!(
+            sources.client_addr(&headers, ip("130.176.1.1")),
+            Some((ip("2001:db8::7").unwrap(), Some(46532)))
+        );
+        // Not sent by an edge, the CDN headers are forged
+        assert_eq!(
+            sources.client_addr(&headers, ip("192.0.2.1")),
+            Some((ip("203.0.113.9").unwrap(), None))
+        );
+
+        assert!(ClientIpSources::new(
+            vec![ClientIpSource::Cdn(CdnPreset::Akamai)],
+            TrustedProxies::default(),
+            &cdn_ranges,
+        )
+        .is_err());
+        assert!(ClientIpSources::new(
+            Vec::new(),
+            TrustedProxies::default(),
+            &HashMap::from([(
+                "fastly".to_string(),
+                dir.join("missing").display().to_string()
+            )]),
+        )
+        .is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+//! Encoding of geo values that cannot be sent as is in a header value, such as city or ISP names
+//! outside of visible ASCII.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Suffix of the header carrying the RFC 8187 value next to its ASCII fallback
+pub const ENCODED_HEADER_SUFFIX: &str = "-Encoded";
+
+/// How values that are not visible ASCII are written in their header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderEncoding {
+    /// `UTF-8''` percent-encoded extended value (RFC 8187)
+    Rfc8187,
+    /// Closest ASCII transliteration (`Montréal` becomes `Montreal`)
+    Transliterate,
+    /// The transliteration, plus the RFC 8187 value in a `<header>-Encoded` header
+    EncodedHeader,
+}
+
+impl FromStr for HeaderEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "rfc8187" => Ok(HeaderEncoding::Rfc8187),
+            "transliterate" => Ok(HeaderEncoding::Transliterate),
+            "encoded_header" => Ok(HeaderEncoding::EncodedHeader),
+            other => Err(format!("Unknown geo header encoding: {}", other)),
+        }
+    }
+}
+
+impl HeaderEncoding {
+    /// Header values to send for `header`, `value` is returned untouched when it is visible ASCII
+    pub fn encode(&self, header: &str, value: &str) -> Vec<(String, String)> {
+        if is_visible_ascii(value) {
+            return vec![(header.to_string(), value.to_string())];
+        }
+
+        match self {
+            HeaderEncoding::Rfc8187 => vec![(header.to_string(), ext_value(value))],
+            HeaderEncoding::Transliterate => vec![(header.to_string(), transliterate(value))],
+            HeaderEncoding::EncodedHeader => vec![
+                (header.to_string(), transliterate(value)),
+                (
+                    format!("{}{}", header, ENCODED_HEADER_SUFFIX),
+                    ext_value(value),
+                ),
+            ],
+        }
+    }
+}
+
+/// Whether `value` only has visible ASCII characters and spaces, which every receiver can read
+fn is_visible_ascii(value: &str) -> bool {
+    value.bytes().all(|b| (0x20..0x7f).contains(&b))
+}
+
+/// RFC 8187 `ext-value` with the UTF-8 charset and no language
+pub fn ext_value(value: &str) -> String {
+    let mut out = String::from("UTF-8''");
+    for b in value.bytes() {
+        if is_attr_char(b) {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{:02X}", b);
+        }
+    }
+    out
+}
+
+fn is_attr_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric()
+        || matches!(
+            b,
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~'
+        )
+}
+
+/// ASCII transliteration of `value`, without the control characters a header cannot carry
+pub fn transliterate(value: &str) -> String {
+    deunicode::deunicode(value)
+        .chars()
+        .filter(|c| !c.is_ascii_control())
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ext_value, transliterate, HeaderEncoding};
+
+    #[test]
+    fn rfc8187() {
+        assert_eq!(ext_value("São Paulo"), "UTF-8''S%C3%A3o%20Paulo");
+        assert_eq!(
+            HeaderEncoding::Rfc8187.encode("Prux-City", "Montréal"),
+            vec![("Prux-City".to_string(), "UTF-8''Montr%C3%A9al".to_string())]
+        );
+        assert_eq!(
+            HeaderEncoding::Rfc8187.encode("Prux-City", "Quebec City"),
+            vec![("Prux-City".to_string(), "Quebec City".to_string())]
+        );
+    }
+
+    #[test]
+    fn transliteration() {
+        assert_eq!(transliterate("São Paulo"), "Sao Paulo");
+        assert_eq!(transliterate("Düsseldorf\r\n"), "Dusseldorf");
+        assert_eq!(
+            HeaderEncoding::EncodedHeader.encode("Prux-City", "Montréal"),
+            vec![
+                ("Prux-City".to_string(), "Montreal".to_string()),
+                (
+                    "Prux-City-Encoded".to_string(),
+                    "UTF-8''Montr%C3%A9al".to_string()
+                ),
+            ]
+        );
+    }
+}
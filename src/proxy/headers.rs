@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use hyper::header::HeaderName;
+
+use crate::geo::field::{GeoField, GeoValue};
+use crate::geo::record::DEFAULT_LOCALE;
+use crate::geo::{GeoLookup, GeoRecord};
+use crate::proxy::encoding::{HeaderEncoding, ENCODED_HEADER_SUFFIX};
+use crate::proxy::structured;
+use prux::signature::Signer;
+
+/// Lowercased prefix of the headers only prux may send upstream
+pub const MANAGED_PREFIX: &str = "prux-";
+
+/// Header carrying the whole enrichment in the structured and JSON formats
+pub const DEFAULT_STRUCTURED_HEADER: &str = "Prux-Geo";
+
+/// An enrichment value sent upstream
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoHeader {
+    /// Key of the value in the structured header
+    pub key: &'static str,
+    /// Header carrying the value when sent as its own header
+    pub header: String,
+    pub value: GeoValue,
+}
+
+impl GeoHeader {
+    pub fn new(key: &'static str, header: &str, value: GeoValue) -> Self {
+        GeoHeader {
+            key,
+            header: header.to_string(),
+            value,
+        }
+    }
+}
+
+/// Enrichment gathered for a request, in insertion order
+#[derive(Debug, Clone, Default)]
+pub struct GeoHeaders(Vec<GeoHeader>);
+
+impl GeoHeaders {
+    /// Adds `header`, replacing any value previously added under the same key
+    pub fn insert(&mut self, header: GeoHeader) {
+        match self.0.iter_mut().find(|h| h.key == header.key) {
+            Some(existing) => *existing = header,
+            None => self.0.push(header),
+        }
+    }
+
+    /// Value sent under the header `name`
+    #[allow(unused)]
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.0
+            .iter()
+            .find(|h| h.header.eq_ignore_ascii_case(name))
+            .map(|h| h.value.to_string())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &GeoHeader> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// How the enrichment is written on the upstream request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderFormat {
+    /// One header per value
+    Headers,
+    /// A single RFC 8941 dictionary header, non ASCII strings following the header encoding
+    Structured,
+    /// A single RFC 9651 dictionary header, non ASCII strings being display strings
+    StructuredRfc9651,
+    /// A single header holding a base64 encoded JSON object
+    Json,
+}
+
+impl FromStr for HeaderFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "headers" => Ok(HeaderFormat::Headers),
+            "structured" => Ok(HeaderFormat::Structured),
+            "structured_rfc9651" => Ok(HeaderFormat::StructuredRfc9651),
+            "json" => Ok(HeaderFormat::Json),
+            other => Err(format!("Unknown geo header format: {}", other)),
+        }
+    }
+}
+
+/// Which geo fields are sent upstream, under which header names and in which format
+#[derive(Debug, Clone)]
+pub struct HeaderMapping {
+    pub locale: String,
+    pub fields: Vec<(GeoField, String)>,
+    pub format: HeaderFormat,
+    /// Header carrying the enrichment in the structured and JSON formats
+    pub structured_header: String,
+    pub encoding: HeaderEncoding,
+    /// Headers removed from incoming requests on top of the ones prux sends, lowercased
+    pub scrubbed_headers: Vec<String>,
+    /// Signs the geo headers sent upstream when set
+    pub signer: Option<Signer>,
+    /// Header listing the resolver that provided each field, for auditing
+    pub sources_header: Option<String>,
+}
+
+impl HeaderMapping {
+    /// Starts from the default `Prux-*` headers and applies `overrides`, a map of field name to
+    /// header name. An empty header name stops sending the field.
+    pub fn new(locale: &str, overrides: &HashMap<String, String>) -> Result<Self, String> {
+        let mut headers = GeoField::ALL
+            .iter()
+            .map(|field| (*field, field.default_header().map(str::to_string)))
+            .collect::<Vec<_>>();
+
+        for (field, header) in overrides {
+            let field = field.parse::<GeoField>()?;
+            let header = header.trim();
+            let header = if header.is_empty() {
+                None
+            } else {
+                HeaderName::from_str(header)
+                    .map_err(|e| format!("Invalid header name {} for {}: {}", header, field, e))?;
+                Some(header.to_string())
+            };
+
+            if let Some(entry) = headers.iter_mut().find(|(f, _)| *f == field) {
+                entry.1 = header;
+            }
+        }
+
+        let locale = locale.trim();
+        Ok(HeaderMapping {
+            locale: if locale.is_empty() {
+                DEFAULT_LOCALE.to_string()
+            } else {
+                locale.to_string()
+            },
+            fields: headers
+                .into_iter()
+                .filter_map(|(field, header)| header.map(|header| (field, header)))
+                .collect(),
+            format: HeaderFormat::Headers,
+            structured_header: DEFAULT_STRUCTURED_HEADER.to_string(),
+            encoding: HeaderEncoding::Transliterate,
+            scrubbed_headers: Vec::new(),
+            signer: None,
+            sources_header: None,
+        })
+    }
+
+    /// Sends the enrichment in `format`, under `structured_header` unless it is one header per value
+    pub fn with_format(
+        mut self,
+        format: HeaderFormat,
+        structured_header: &str,
+    ) -> Result<Self, String> {
+        let structured_header = structured_header.trim();
+        HeaderName::from_str(structured_header).map_err(|e| {
+            format!(
+                "Invalid structured geo header name {}: {}",
+                structured_header, e
+            )
+        })?;
+
+        self.format = format;
+        self.structured_header = structured_header.to_string();
+        Ok(self)
+    }
+
+    /// Encodes values that are not visible ASCII with `encoding` when sending one header per value
+    pub fn with_encoding(mut self, encoding: HeaderEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Also removes `headers` from incoming requests
+    pub fn with_scrubbed_headers(mut self, headers: &[String]) -> Result<Self, String> {
+        self.scrubbed_headers = headers
+            .iter()
+            .map(|header| header.trim())
+            .filter(|header| !header.is_empty())
+            .map(|header| {
+                HeaderName::from_str(header)
+                    .map(|name| name.as_str().to_string())
+                    .map_err(|e| format!("Invalid scrubbed header name {}: {}", header, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(self)
+    }
+
+    pub fn with_signer(mut self, signer: Option<Signer>) -> Self {
+        self.signer = signer;
+        self
+    }
+
+    pub fn with_sources_header(mut self, header: Option<&str>) -> Result<Self, String> {
+        self.sources_header = header
+            .map(str::trim)
+            .filter(|header| !header.is_empty())
+            .map(|header| {
+                HeaderName::from_str(header)
+                    .map(|_| header.to_string())
+                    .map_err(|e| format!("Invalid geo sources header name {}: {}", header, e))
+            })
+            .transpose()?;
+        Ok(self)
+    }
+
+    /// Whether a client supplied `name` header must be removed so that only prux can set it:
+    /// anything in the `Prux-*` namespace, the configured geo headers and the scrubbed headers
+    pub fn is_managed(&self, name: &HeaderName) -> bool {
+        let name = name.as_str();
+        let managed = |header: &str| {
+            name.eq_ignore_ascii_case(header)
+                || name
+                    .strip_suffix(&ENCODED_HEADER_SUFFIX.to_ascii_lowercase())
+                    .is_some_and(|name| name.eq_ignore_ascii_case(header))
+        };
+
+        name.starts_with(MANAGED_PREFIX)
+            || managed(&self.structured_header)
+            || self.sources_header.as_deref().is_some_and(managed)
+            || self.fields.iter().any(|(_, header)| managed(header))
+            || self.scrubbed_headers.iter().any(|header| header == name)
+            || self
+                .signer
+                .as_ref()
+                .is_some_and(|signer| signer.header.as_str() == name)
+    }
+
+    /// Mapped headers with their value in `record`, fields missing from the record are skipped
+    pub fn values<'a>(&'a self, record: &'a GeoRecord) -> impl Iterator<Item = GeoHeader> + 'a {
+        self.fields.iter().filter_map(move |(field, header)| {
+            field
+                .value(record, &self.locale)
+                .map(|value| GeoHeader::new(field.name(), header, value))
+        })
+    }
+
+    /// Header listing `field=source` for the mapped fields found in `lookup`, when enabled
+    pub fn sources(&self, lookup: &GeoLookup) -> Option<GeoHeader> {
+        let header = self.sources_header.as_ref()?;
+        let sources = self
+            .fields
+            .iter()
+            .filter(|(field, _)| field.value(&lookup.record, &self.locale).is_some())
+            .map(|(field, _)| format!("{}={}", field, lookup.field_source(*field)))
+            .collect::<Vec<_>>();
+
+        Some(GeoHeader::new(
+            "geo_sources",
+            header,
+            GeoValue::String(sources.join(", ")),
+        ))
+    }
+
+    /// Header names and values to add to the upstream request for `headers`
+    pub fn render(&self, headers: &GeoHeaders) -> Vec<(String, String)> {
+        if headers.is_empty() {
+            return Vec::new();
+        }
+
+        match self.format {
+            HeaderFormat::Headers => headers
+                .iter()
+                .flat_map(|h| self.encoding.encode(&h.header, &h.value.to_string()))
+                .collect(),
+            HeaderFormat::Structured => vec![(
+                self.structured_header.clone(),
+                structured::to_dictionary(headers.iter(), self.encoding),
+            )],
+            HeaderFormat::StructuredRfc9651 => vec![(
+                self.structured_header.clone(),
+                structured::to_display_dictionary(headers.iter()),
+            )],
+            HeaderFormat::Json => vec![(
+                self.structured_header.clone(),
+                structured::to_base64_json(headers.iter()),
+            )],
+        }
+    }
+}
+
+impl Default for HeaderMapping {
+    fn default() -> Self {
+        HeaderMapping::new(DEFAULT_LOCALE, &HashMap::new())
+            .expect("Default geo headers must be valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GeoHeaders, HeaderFormat, HeaderMapping};
+    use crate::geo::field::GeoField;
+    use crate::geo::GeoLookup;
+    use crate::geo::GeoRecord;
+    use hyper::header::HeaderName;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    fn record() -> GeoRecord {
+        serde_json::from_value(json!({
+            "continent": { "code": "EU", "names": { "en": "Europe" } },
+            "country": { "iso_code": "FR", "is_in_european_union": true, "names": { "en": "France" } },
+            "postal": { "code": "75001", "confidence": 40 },
+            "traits": { "autonomous_system_number": 3215, "isp": "Orange", "user_type": "residential" }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn rename_add_and_remove_headers() {
+        let overrides = [
+            ("isp", "X-Geo-ISP"),
+            ("postal_code", "X-Geo-Postal"),
+            ("is_in_european_union", "X-Geo-EU"),
+            ("country_name", ""),
+        ]
+        .iter()
+        .map(|(field, header)| (field.to_string(), header.to_string()))
+        .collect::<HashMap<_, _>>();
+        let mapping = HeaderMapping::new("en", &overrides).unwrap();
+        let record = record();
+
+        let headers = mapping
+            .values(&record)
+            .map(|h| (h.header, h.value.to_string()))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(headers.get("X-Geo-ISP").map(String::as_str), Some("Orange"));
+        assert_eq!(
+            headers.get("X-Geo-Postal").map(String::as_str),
+            Some("75001")
+        );
+        assert_eq!(headers.get("X-Geo-EU").map(String::as_str), Some("true"));
+        assert!(!headers.contains_key("Prux-Country"));
+        assert!(!headers.contains_key("Prux-ISP"));
+    }
+
+    #[test]
+    fn invalid_mapping() {
+        let unknown = HashMap::from([("altitude".to_string(), "X-Geo-Alt".to_string())]);
+        assert!(HeaderMapping::new("en", &unknown).is_err());
+
+        let invalid = HashMap::from([("isp".to_string(), "X Geo ISP".to_string())]);
+        assert!(HeaderMapping::new("en", &invalid).is_err());
+    }
+
+    #[test]
+    fn structured_formats() {
+        let record = record();
+        let mut headers = GeoHeaders::default();
+        for header in HeaderMapping::default().values(&record) {
+            headers.insert(header);
+        }
+
+        let mapping = HeaderMapping::default()
+            .with_format(HeaderFormat::Structured, "X-Geo")
+            .unwrap();
+        assert_eq!(
+            mapping.render(&headers),
+            vec![(
+                "X-Geo".to_string(),
+                r#"country_name="France", isp="Orange""#.to_string()
+            )]
+        );
+
+        // Accented names stay readable by RFC 8941 parsers with the default encoding
+        let record = serde_json::from_value::<GeoRecord>(json!({
+            "city": { "names": { "en": "Montreal", "fr": "Montréal" } }
+        }))
+        .unwrap();
+        let mapping = HeaderMapping::new("fr", &HashMap::new()).unwrap();
+        let mut accented = GeoHeaders::default();
+        for header in mapping.values(&record) {
+            accented.insert(header);
+        }
+        let mapping = mapping
+            .with_format(HeaderFormat::Structured, "X-Geo")
+            .unwrap();
+        assert_eq!(mapping.render(&accented)[0].1, r#"city_name="Montreal""#);
+        let mapping = mapping
+            .with_format(HeaderFormat::StructuredRfc9651, "X-Geo")
+            .unwrap();
+        assert_eq!(
+            mapping.render(&accented)[0].1,
+            r#"city_name=%"Montr%c3%a9al""#
+        );
+
+        let mapping = HeaderMapping::default();
+        assert_eq!(mapping.render(&headers).len(), 2);
+        assert!(mapping.render(&GeoHeaders::default()).is_empty());
+        assert!("yaml".parse::<HeaderFormat>().is_err());
+    }
+
+    #[test]
+    fn managed_headers() {
+        let overrides = HashMap::from([("isp".to_string(), "X-Geo-ISP".to_string())]);
+        let mapping = HeaderMapping::new("en", &overrides)
+            .unwrap()
+            .with_format(HeaderFormat::Json, "X-Geo")
+            .unwrap()
+            .with_scrubbed_headers(&["X-Client-Country".to_string(), " ".to_string()])
+            .unwrap();
+
+        for header in [
+            "prux-country",
+            "Prux-Addr",
+            "x-geo-isp",
+            "x-geo-isp-encoded",
+            "x-geo",
+            "x-client-country",
+        ] {
+            assert!(mapping.is_managed(&HeaderName::from_str(header).unwrap()));
+        }
+        assert!(!mapping.is_managed(&HeaderName::from_static("x-forwarded-for")));
+        assert!(!mapping.is_managed(&HeaderName::from_static("x-geo-city")));
+        assert!(HeaderMapping::default()
+            .with_scrubbed_headers(&["Bad Header".to_string()])
+            .is_err());
+    }
+
+    #[test]
+    fn sources_header() {
+        let lookup = GeoLookup {
+            record: Arc::new(record()),
+            source: "mmdb",
+            field_sources: vec![(GeoField::Isp, "maxmind")],
+            cached: false,
+        };
+
+        assert!(HeaderMapping::default().sources(&lookup).is_none());
+        let mapping = HeaderMapping::default()
+            .with_sources_header(Some("Prux-Geo-Sources"))
+            .unwrap();
+        assert_eq!(
+            mapping.sources(&lookup).map(|h| h.value.to_string()),
+            Some("country_name=mmdb, isp=maxmind".to_string())
+        );
+    }
+}
@@ -1,32 +1,58 @@
-use std::collections::HashMap;
 use std::net::IpAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 
 use ::futures;
 use futures::task::{Context, Poll};
 use futures::Future;
-use hyper::client::HttpConnector;
 use hyper::service::Service;
-use hyper::{Body, Client, Response, Uri};
+use hyper::{Body, Client, HeaderMap, Response, Uri};
 use hyper_tls::HttpsConnector;
-use log::error;
+use log::{debug, error, warn};
 
+use crate::geo::cdn::{chain_with_cdn, record_from_headers};
+use crate::geo::overrides::NetworkOverrides;
+use crate::geo::{GeoResolver, ServiceTier};
+use crate::proxy::classify::{canonical_ip, IpClassifier};
+use crate::proxy::client_ip::ClientIpSources;
+use crate::proxy::headers::{GeoHeaders, HeaderMapping};
+use crate::proxy::policy::{FailureMode, GeoFailurePolicy};
+use crate::proxy::proxy_protocol::UpstreamConnector;
 use crate::proxy::utils::*;
 use crate::utils::UriPathMatcher;
-use crate::IpResolver;
 
+pub mod classify;
+pub mod client_ip;
+pub mod encoding;
+pub mod headers;
+pub mod policy;
+pub mod proxy_protocol;
+pub mod structured;
 pub mod utils;
 
+/// Client of the upstream server
+pub type UpstreamClient = Client<HttpsConnector<UpstreamConnector>>;
+
 pub struct Proxy {
     pub upstream_uri: Uri,
     pub source_ip: Option<IpAddr>,
-    pub resolver: IpResolver,
-    pub client: Client<HttpsConnector<HttpConnector>>,
+    pub source_port: Option<u16>,
+    pub resolver: Arc<dyn GeoResolver>,
+    pub client: UpstreamClient,
     pub ip_path_inclusions: Vec<UriPathMatcher>,
     pub maxmind_path_inclusions: Vec<UriPathMatcher>,
+    pub maxmind_tier_path_inclusions: Vec<(ServiceTier, Vec<UriPathMatcher>)>,
+    pub maxmind_service: ServiceTier,
     pub path_exclusions: Option<Vec<UriPathMatcher>>,
-    pub forwarded_ip_header: Option<String>,
+    pub client_ip_sources: Arc<ClientIpSources>,
     pub use_forwarded_ip_header_only: bool,
+    pub failure_policy: Arc<GeoFailurePolicy>,
+    pub header_mapping: Arc<HeaderMapping>,
+    pub network_overrides: Arc<NetworkOverrides>,
+    pub ip_classifier: Arc<IpClassifier>,
+    pub send_client_port: bool,
+    /// Whether the geo headers of trusted CDN edges are used before the resolver
+    pub cdn_geo_headers: bool,
 }
 
 impl Proxy {
@@ -34,34 +60,35 @@ impl Proxy {
     pub fn new(
         upstream_uri: Uri,
         source_ip: Option<IpAddr>,
-        resolver: IpResolver,
-        client: Client<HttpsConnector<HttpConnector>>,
+        source_port: Option<u16>,
+        resolver: Arc<dyn GeoResolver>,
+        client: UpstreamClient,
         ip_inclusions: Vec<String>,
         maxmind_inclusions: Vec<String>,
+        maxmind_tier_inclusions: Vec<(ServiceTier, Vec<String>)>,
+        maxmind_service: ServiceTier,
         exclusions: Option<Vec<String>>,
-        forwarded_ip_header: Option<String>,
+        client_ip_sources: Arc<ClientIpSources>,
         use_forwarded_ip_header_only: bool,
+        failure_policy: Arc<GeoFailurePolicy>,
+        header_mapping: Arc<HeaderMapping>,
+        network_overrides: Arc<NetworkOverrides>,
+        ip_classifier: Arc<IpClassifier>,
+        send_client_port: bool,
+        cdn_geo_headers: bool,
     ) -> Self {
         Proxy {
             upstream_uri,
             source_ip,
+            source_port,
             client,
-            ip_path_inclusions: ip_inclusions
-                .iter()
-                .filter_map(|p| {
-                    UriPathMatcher::new(p)
-                        .map_err(|e| error!("Unable to construct included middleware route: {}", e))
-                        .ok()
-                })
-                .collect(),
-            maxmind_path_inclusions: maxmind_inclusions
+            ip_path_inclusions: included_path_matchers(&ip_inclusions),
+            maxmind_path_inclusions: included_path_matchers(&maxmind_inclusions),
+            maxmind_tier_path_inclusions: maxmind_tier_inclusions
                 .iter()
-                .filter_map(|p| {
-                    UriPathMatcher::new(p)
-                        .map_err(|e| error!("Unable to construct included middleware route: {}", e))
-                        .ok()
-                })
+                .map(|(tier, paths)| (*tier, included_path_matchers(paths)))
                 .collect(),
+            maxmind_service,
             path_exclusions: exclusions.map(|ex| {
                 ex.iter()
                     .filter_map(|p| {
@@ -74,8 +101,14 @@ impl Proxy {
                     .collect()
             }),
             resolver,
-            forwarded_ip_header,
+            client_ip_sources,
             use_forwarded_ip_header_only,
+            failure_policy,
+            header_mapping,
+            network_overrides,
+            ip_classifier,
+            send_client_port,
+            cdn_geo_headers,
         }
     }
 
@@ -99,6 +132,39 @@ impl Proxy {
         false
     }
 
+    /// Service tier to query for `path`, `None` when the path does not need geo data
+    pub fn maxmind_tier(&self, path: &str) -> Option<ServiceTier> {
+        if let Some(ref path_exclusions) = self.path_exclusions {
+            if path_exclusions.iter().any(|m_e_p| m_e_p.match_start(path)) {
+                return None;
+            }
+        }
+
+        // Tier lists are ordered from the most detailed tier, which also covers the cheaper ones
+        self.maxmind_tier_path_inclusions
+            .iter()
+            .find(|(_, inclusions)| inclusions.iter().any(|m_p| m_p.match_start(path)))
+            .map(|(tier, _)| *tier)
+            .or_else(|| {
+                self.validate_maxmind_path(path)
+                    .then_some(self.maxmind_service)
+            })
+    }
+
+    /// Resolver answering with the geo headers of the CDN edge that sent the request, asking
+    /// the configured resolver for the fields it did not send
+    fn cdn_resolver(&self, headers: &HeaderMap, mapping: &HeaderMapping) -> Arc<dyn GeoResolver> {
+        let record = self
+            .client_ip_sources
+            .cdn(self.source_ip)
+            .and_then(|cdn| Some((cdn, record_from_headers(cdn, headers)?)));
+
+        match record {
+            Some((cdn, record)) => chain_with_cdn(cdn, record, self.resolver.clone(), mapping),
+            None => self.resolver.clone(),
+        }
+    }
+
     pub fn validate_maxmind_path(&self, path: &str) -> bool {
         if self.maxmind_path_inclusions.is_empty() {
             return true;
@@ -120,6 +186,17 @@ impl Proxy {
     }
 }
 
+pub(crate) fn included_path_matchers(paths: &[String]) -> Vec<UriPathMatcher> {
+    paths
+        .iter()
+        .filter_map(|p| {
+            UriPathMatcher::new(p)
+                .map_err(|e| error!("Unable to construct included middleware route: {}", e))
+                .ok()
+        })
+        .collect()
+}
+
 impl Service<hyper::Request<hyper::Body>> for Proxy {
     type Response = Response<Body>;
     type Error = StringError;
@@ -135,35 +212,64 @@ impl Service<hyper::Request<hyper::Body>> for Proxy {
 
         let upstream_uri = Uri::from_parts(upstream_parts).expect("Url must be valid");
 
-        let valid_maxmind = self.validate_maxmind_path(upstream_uri.path());
+        let maxmind_tier = self.maxmind_tier(upstream_uri.path());
         let valid_ip = self.validate_ip_path(upstream_uri.path());
 
-        let forwarded_ip = get_forwarded_ip(
-            &req,
-            self.forwarded_ip_header.as_deref(),
-            self.use_forwarded_ip_header_only,
-        );
+        let forwarded_ip = get_forwarded_ip(&req, &self.client_ip_sources, self.source_ip);
 
+        // Non global addresses are only located when an override covers them
+        let locatable =
+            |ip: &IpAddr| self.ip_classifier.is_global(ip) || self.network_overrides.contains(ip);
         let forwarded_ip = if self.use_forwarded_ip_header_only {
-            forwarded_ip.filter(ip_is_global)
+            forwarded_ip
         } else {
-            forwarded_ip.or(self.source_ip).filter(ip_is_global)
-        };
+            forwarded_ip.or(self.source_ip.map(|ip| (ip, self.source_port)))
+        }
+        .map(|(ip, port)| (canonical_ip(ip), port))
+        .filter(|(ip, _)| locatable(ip));
+        let send_client_port = self.send_client_port;
 
+        let failure_mode = self.failure_policy.mode_for(upstream_uri.path());
+        let failure_policy = self.failure_policy.clone();
         let client = self.client.clone();
-        let resolver = self.resolver.clone();
+        let header_mapping = self.header_mapping.clone();
+        let resolver = match forwarded_ip {
+            Some((ip, _)) if self.cdn_geo_headers && !self.network_overrides.contains(&ip) => {
+                self.cdn_resolver(req.headers(), &header_mapping)
+            }
+            _ => self.resolver.clone(),
+        };
 
         Box::pin(async move {
-            let headers = if let Some(ip) = forwarded_ip {
-                let mut hdr_map = HashMap::new();
-                if valid_ip || valid_maxmind {
+            let headers = if let Some((ip, port)) = forwarded_ip {
+                let mut hdr_map = GeoHeaders::default();
+                if valid_ip || maxmind_tier.is_some() {
                     utils::add_ip_hdr(&ip, &mut hdr_map).await;
+                    if let Some(port) = port.filter(|_| send_client_port) {
+                        utils::add_port_hdr(port, &mut hdr_map);
+                    }
                 }
 
-                if valid_maxmind {
-                    utils::get_location_hdr(ip, resolver, &mut hdr_map)
-                        .await
-                        .map_err(|_| StringError("injection failed".to_string()))?;
+                if let Some(tier) = maxmind_tier {
+                    if let Err(e) =
+                        utils::get_location_hdr(ip, tier, resolver, &header_mapping, &mut hdr_map)
+                            .await
+                    {
+                        if e.needs_attention() {
+                            error!("Geo lookup for {} failed: {}", ip, e);
+                        } else if e.is_permanent() {
+                            debug!("Geo lookup for {} failed: {}", ip, e);
+                        } else {
+                            warn!("Geo lookup for {} failed: {}", ip, e);
+                        }
+
+                        // Addresses the provider does not know are not a provider failure
+                        if failure_mode == FailureMode::Closed && !e.is_permanent() {
+                            return Ok(failure_policy.response());
+                        }
+
+                        utils::add_geo_status_hdr(&e, &mut hdr_map);
+                    }
                 }
 
                 Some(hdr_map)
@@ -171,8 +277,90 @@ impl Service<hyper::Request<hyper::Body>> for Proxy {
                 None
             };
 
-            let request = construct_request(req, upstream_uri, headers);
+            let request = construct_request(req, upstream_uri, headers, &header_mapping);
             Ok(gen_transmit_fut(&client, request).await)
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::policy::{FailureMode, GeoFailurePolicy};
+    use super::proxy_protocol::UpstreamConnector;
+    use super::Proxy;
+    use crate::geo::testing::FixedResolver;
+    use crate::geo::{GeoError, ServiceTier};
+    use crate::http::request::tests::spawn_mock_maxmind;
+    use hyper::service::Service;
+    use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+    use hyper_tls::HttpsConnector;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tokio_native_tls::{native_tls, TlsConnector};
+
+    fn closed_proxy(upstream: &str, error: GeoError) -> Proxy {
+        let client = Client::builder().build::<_, Body>(HttpsConnector::from((
+            UpstreamConnector::new(None),
+            TlsConnector::from(native_tls::TlsConnector::new().unwrap()),
+        )));
+        let failure_policy = GeoFailurePolicy::new(
+            FailureMode::Closed,
+            Vec::new(),
+            Vec::new(),
+            503,
+            "text/plain",
+            "unavailable".to_string(),
+        )
+        .unwrap();
+
+        Proxy::new(
+            Uri::from_str(upstream).unwrap(),
+            Some(IpAddr::from_str("24.201.0.1").unwrap()),
+            Some(52044),
+            FixedResolver::failing("maxmind", error),
+            client,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            ServiceTier::City,
+            None,
+            Default::default(),
+            false,
+            Arc::new(failure_policy),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            false,
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn fail_closed_only_when_provider_fails() {
+        let (upstream, hits) = spawn_mock_maxmind(|req| {
+            let mut res = Response::new(Body::empty());
+            if let Some(status) = req.headers().get("Prux-Geo-Status") {
+                res.headers_mut().insert("Prux-Geo-Status", status.clone());
+            }
+            res
+        })
+        .await;
+        let request = || Request::get("/checkout").body(Body::empty()).unwrap();
+
+        let response = closed_proxy(&upstream, GeoError::Timeout)
+            .call(request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+
+        let response = closed_proxy(&upstream, GeoError::AddressNotFound)
+            .call(request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Prux-Geo-Status"], "unknown");
+    }
+}
@@ -0,0 +1,127 @@
+use std::str::FromStr;
+
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+
+use crate::proxy::included_path_matchers;
+use crate::utils::UriPathMatcher;
+
+/// What to do with a request when its geo enrichment fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureMode {
+    /// Forward the request without geo headers
+    Open,
+    /// Answer the request with the configured failure page when the provider fails, addresses
+    /// it does not know are still forwarded
+    Closed,
+}
+
+impl FromStr for FailureMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "open" => Ok(FailureMode::Open),
+            "closed" => Ok(FailureMode::Closed),
+            other => Err(format!("Unknown geo failure policy: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GeoFailurePolicy {
+    pub mode: FailureMode,
+    pub fail_open_paths: Vec<UriPathMatcher>,
+    pub fail_closed_paths: Vec<UriPathMatcher>,
+    pub status: StatusCode,
+    pub content_type: HeaderValue,
+    pub body: String,
+}
+
+impl GeoFailurePolicy {
+    pub fn new(
+        mode: FailureMode,
+        fail_open_paths: Vec<String>,
+        fail_closed_paths: Vec<String>,
+        status: u16,
+        content_type: &str,
+        body: String,
+    ) -> Result<Self, String> {
+        Ok(GeoFailurePolicy {
+            mode,
+            fail_open_paths: included_path_matchers(&fail_open_paths),
+            fail_closed_paths: included_path_matchers(&fail_closed_paths),
+            status: StatusCode::from_u16(status)
+                .map_err(|e| format!("Invalid geo failure status {}: {}", status, e))?,
+            content_type: HeaderValue::from_str(content_type)
+                .map_err(|e| format!("Invalid geo failure content type: {}", e))?,
+            body,
+        })
+    }
+
+    /// Failure mode for `path`, routes listed for one mode take precedence over the default
+    pub fn mode_for(&self, path: &str) -> FailureMode {
+        if self
+            .fail_closed_paths
+            .iter()
+            .any(|m_p| m_p.match_start(path))
+        {
+            FailureMode::Closed
+        } else if self.fail_open_paths.iter().any(|m_p| m_p.match_start(path)) {
+            FailureMode::Open
+        } else {
+            self.mode
+        }
+    }
+
+    /// Response sent to the client when failing closed
+    pub fn response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, self.content_type.clone());
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FailureMode, GeoFailurePolicy};
+    use hyper::StatusCode;
+
+    #[test]
+    fn per_route_mode() {
+        let policy = GeoFailurePolicy::new(
+            FailureMode::Open,
+            Vec::new(),
+            vec!["/payments".to_string(), "/<_>/compliance".to_string()],
+            503,
+            "text/html",
+            "<h1>Unavailable</h1>".to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(policy.mode_for("/payments/checkout"), FailureMode::Closed);
+        assert_eq!(policy.mode_for("/v2/compliance"), FailureMode::Closed);
+        assert_eq!(policy.mode_for("/home"), FailureMode::Open);
+
+        let response = policy.response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["content-type"], "text/html");
+    }
+
+    #[test]
+    fn invalid_policy() {
+        assert!("half-open".parse::<FailureMode>().is_err());
+        assert!(GeoFailurePolicy::new(
+            FailureMode::Closed,
+            Vec::new(),
+            Vec::new(),
+            42,
+            "text/plain",
+            String::new()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,534 @@
+//! PROXY protocol, versions 1 and 2, as sent by L4 load balancers such as HAProxy in TCP mode
+//! or AWS NLB. Incoming connections may start with a header carrying the client address, and
+//! upstream connections may be started with one so that the upstream sees the client as well.
+
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::proxy::utils::TrustedProxies;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest version 1 header, CRLF included
+const V1_MAX_LEN: usize = 107;
+/// Time given to a peer to send its header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocolMode {
+    Off,
+    /// Connections may start with a header
+    Optional,
+    /// Connections without a header are dropped
+    Required,
+}
+
+impl FromStr for ProxyProtocolMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Ok(ProxyProtocolMode::Off),
+            "optional" => Ok(ProxyProtocolMode::Optional),
+            "required" => Ok(ProxyProtocolMode::Required),
+            other => Err(format!("Unknown proxy protocol mode: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+impl FromStr for ProxyProtocolVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "1" | "v1" => Ok(ProxyProtocolVersion::V1),
+            "2" | "v2" => Ok(ProxyProtocolVersion::V2),
+            other => Err(format!("Unknown proxy protocol version: {}", other)),
+        }
+    }
+}
+
+/// Addresses of the connection between the client and the load balancer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxiedAddrs {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyProtocolError {
+    Missing,
+    Malformed(&'static str),
+    Io(String),
+}
+
+impl fmt::Display for ProxyProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyProtocolError::Missing => write!(f, "proxy protocol header is missing"),
+            ProxyProtocolError::Malformed(reason) => {
+                write!(f, "proxy protocol header is malformed: {}", reason)
+            }
+            ProxyProtocolError::Io(e) => write!(f, "unable to read proxy protocol header: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProxyProtocolError {}
+
+impl From<std::io::Error> for ProxyProtocolError {
+    fn from(e: std::io::Error) -> Self {
+        ProxyProtocolError::Io(e.to_string())
+    }
+}
+
+/// Reads the PROXY protocol header of accepted connections
+#[derive(Debug)]
+pub struct ProxyProtocolListener {
+    mode: ProxyProtocolMode,
+    /// Peers allowed to send a header, the others are served as if the mode was off
+    sources: TrustedProxies,
+}
+
+impl ProxyProtocolListener {
+    /// `sources` are required unless the mode is off, the header carrying the address every
+    /// client trust decision is based on
+    pub fn new(mode: ProxyProtocolMode, sources: &[String]) -> Result<Self, String> {
+        let sources = TrustedProxies::new(sources)
+            .map_err(|e| format!("Invalid proxy protocol source: {}", e))?;
+        if mode != ProxyProtocolMode::Off && sources.is_empty() {
+            return Err(
+                "proxy_protocol_sources must list the load balancers sending the header"
+                    .to_string(),
+            );
+        }
+
+        Ok(ProxyProtocolListener { mode, sources })
+    }
+
+    /// Consumes the header of a connection accepted from `peer`. Returns the addresses it
+    /// carries, `None` when the peer sent no header or one for a connection of its own, such as
+    /// a health check.
+    pub async fn accept(
+        &self,
+        stream: &mut TcpStream,
+        peer: SocketAddr,
+    ) -> Result<Option<ProxiedAddrs>, ProxyProtocolError> {
+        if self.mode == ProxyProtocolMode::Off || !self.sources.contains(&peer.ip()) {
+            return Ok(None);
+        }
+
+        tokio::time::timeout(HEADER_TIMEOUT, async {
+            if has_header(stream).await? {
+                read_header(stream).await
+            } else if self.mode == ProxyProtocolMode::Required {
+                Err(ProxyProtocolError::Missing)
+            } else {
+                Ok(None)
+            }
+        })
+        .await
+        .map_err(|_| ProxyProtocolError::Io("timed out".to_string()))?
+    }
+}
+
+/// Whether the connection starts with a header, without consuming anything
+async fn has_header(stream: &TcpStream) -> Result<bool, ProxyProtocolError> {
+    let mut buf = [0; 12];
+    loop {
+        let len = stream.peek(&mut buf).await?;
+        if len == 0 {
+            return Err(ProxyProtocolError::Io("connection closed".to_string()));
+        }
+
+        let data = &buf[..len];
+        let v2 = V2_SIGNATURE.starts_with(&data[..len.min(V2_SIGNATURE.len())]);
+        let v1 = data.starts_with(V1_PREFIX) || V1_PREFIX.starts_with(data);
+        match (v1, v2) {
+            (false, false) => return Ok(false),
+            _ if len >= V2_SIGNATURE.len() => return Ok(true),
+            // Only part of the header arrived, peeking again right away would return the same
+            _ => tokio::time::sleep(Duration::from_millis(5)).await,
+        }
+    }
+}
+
+/// Reads a version 1 or 2 header from the start of `stream`
+pub async fn read_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<Option<ProxiedAddrs>, ProxyProtocolError> {
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(V1_PREFIX) {
+        read_v1(stream, &start).await
+    } else {
+        Err(ProxyProtocolError::Missing)
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    start: &[u8],
+) -> Result<Option<ProxiedAddrs>, ProxyProtocolError> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(ProxyProtocolError::Malformed("line too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| ProxyProtocolError::Malformed("not ASCII"))?;
+    let parts = line.split(' ').collect::<Vec<_>>();
+    let malformed = || ProxyProtocolError::Malformed("invalid addresses");
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] =>
+        {
+            let ip = |s: &str| -> Result<IpAddr, ProxyProtocolError> {
+                match *family {
+                    "TCP4" => Ipv4Addr::from_str(s).map(IpAddr::V4),
+                    _ => Ipv6Addr::from_str(s).map(IpAddr::V6),
+                }
+                .map_err(|_| malformed())
+            };
+            let port = |s: &str| s.parse::<u16>().map_err(|_| malformed());
+            Ok(Some(ProxiedAddrs {
+                source: SocketAddr::new(ip(source)?, port(source_port)?),
+                destination: SocketAddr::new(ip(destination)?, port(destination_port)?),
+            }))
+        }
+        _ => Err(ProxyProtocolError::Malformed("invalid line")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<Option<ProxiedAddrs>, ProxyProtocolError> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await?;
+    // Addresses are followed by optional TLVs, which are skipped
+    let mut payload = vec![0; usize::from(len)];
+    stream.read_exact(&mut payload).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(ProxyProtocolError::Malformed("unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, the balancer's own connection
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(ProxyProtocolError::Malformed("unsupported command")),
+    }
+
+    let too_short = ProxyProtocolError::Malformed("addresses too short");
+    let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+    match family >> 4 {
+        // AF_INET
+        0x1 => {
+            let a = payload.get(..12).ok_or(too_short)?;
+            let ip =
+                |bytes: &[u8]| IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]));
+            Ok(Some(ProxiedAddrs {
+                source: SocketAddr::new(ip(&a[0..4]), port(&a[8..10])),
+                destination: SocketAddr::new(ip(&a[4..8]), port(&a[10..12])),
+            }))
+        }
+        // AF_INET6
+        0x2 => {
+            let a = payload.get(..36).ok_or(too_short)?;
+            let ip = |bytes: &[u8]| {
+                IpAddr::V6(Ipv6Addr::from(
+                    <[u8; 16]>::try_from(bytes).expect("Slice is 16 bytes long"),
+                ))
+            };
+            Ok(Some(ProxiedAddrs {
+                source: SocketAddr::new(ip(&a[0..16]), port(&a[32..34])),
+                destination: SocketAddr::new(ip(&a[16..32]), port(&a[34..36])),
+            }))
+        }
+        // AF_UNSPEC and AF_UNIX carry no address prux can use
+        _ => Ok(None),
+    }
+}
+
+/// Header announcing `addrs` to the upstream. Addresses of different families are both sent
+/// as IPv6.
+pub fn encode(version: ProxyProtocolVersion, addrs: &ProxiedAddrs) -> Vec<u8> {
+    let (source, destination) = match (addrs.source.ip(), addrs.destination.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+        (s, d) => (IpAddr::V6(to_ipv6(s)), IpAddr::V6(to_ipv6(d))),
+    };
+    let (source_port, destination_port) = (addrs.source.port(), addrs.destination.port());
+
+    match version {
+        ProxyProtocolVersion::V1 => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if source.is_ipv4() { "TCP4" } else { "TCP6" },
+            source,
+            destination,
+            source_port,
+            destination_port
+        )
+        .into_bytes(),
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // Version 2, PROXY command
+            header.push(0x21);
+            match (source, destination) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => {
+                    header.extend([0x11, 0, 12]);
+                    header.extend(s.octets());
+                    header.extend(d.octets());
+                }
+                (s, d) => {
+                    header.extend([0x21, 0, 36]);
+                    header.extend(to_ipv6(s).octets());
+                    header.extend(to_ipv6(d).octets());
+                }
+            }
+            header.extend(source_port.to_be_bytes());
+            header.extend(destination_port.to_be_bytes());
+            header
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Connects to the upstream, starting each connection with `header` when set
+#[derive(Debug, Clone)]
+pub struct UpstreamConnector {
+    http: HttpConnector,
+    header: Option<Bytes>,
+}
+
+impl UpstreamConnector {
+    pub fn new(header: Option<Vec<u8>>) -> Self {
+        let mut http = HttpConnector::new();
+        // TLS is handled by the wrapping connector
+        http.enforce_http(false);
+        UpstreamConnector {
+            http,
+            header: header.map(Bytes::from),
+        }
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = TcpStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.http.call(uri);
+        let header = self.header.clone();
+        Box::pin(async move {
+            let mut stream = connecting.await?;
+            if let Some(header) = header {
+                stream.write_all(&header).await?;
+            }
+            Ok(stream)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        encode, read_header, ProxiedAddrs, ProxyProtocolError, ProxyProtocolListener,
+        ProxyProtocolMode, ProxyProtocolVersion,
+    };
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn addrs(source: &str, destination: &str) -> ProxiedAddrs {
+        ProxiedAddrs {
+            source: SocketAddr::from_str(source).unwrap(),
+            destination: SocketAddr::from_str(destination).unwrap(),
+        }
+    }
+
+    /// Accepts a local connection whose peer sends `chunks`, pausing between them
+    async fn connection(chunks: &'static [&'static [u8]]) -> (TcpStream, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.set_nodelay(true).unwrap();
+            for chunk in chunks {
+                client.write_all(chunk).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        listener.accept().await.unwrap()
+    }
+
+    async fn remaining(mut stream: TcpStream) -> Vec<u8> {
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).await.unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn accept_from_sources() {
+        const HEADER: &[u8] = b"PROXY TCP4 192.0.2.7 127.0.0.1 56324 443\r\n";
+        let loopback =
+            ProxyProtocolListener::new(ProxyProtocolMode::Required, &["127.0.0.0/8".to_string()])
+                .unwrap();
+
+        let (mut stream, peer) = connection(&[HEADER, b"GET / HTTP/1.1\r\n"]).await;
+        assert_eq!(
+            loopback.accept(&mut stream, peer).await,
+            Ok(Some(addrs("192.0.2.7:56324", "127.0.0.1:443")))
+        );
+        assert_eq!(remaining(stream).await, b"GET / HTTP/1.1\r\n");
+
+        // Split across packets, the header is only read once complete
+        let (mut stream, peer) =
+            connection(&[b"PROX", b"Y TCP4 192.0.2.7 127.0.0.1", b" 56324 443\r\nGET"]).await;
+        assert_eq!(
+            loopback.accept(&mut stream, peer).await,
+            Ok(Some(addrs("192.0.2.7:56324", "127.0.0.1:443")))
+        );
+        assert_eq!(remaining(stream).await, b"GET");
+
+        let (mut stream, peer) = connection(&[b"GET / HTTP/1.1\r\n"]).await;
+        assert_eq!(
+            loopback.accept(&mut stream, peer).await,
+            Err(ProxyProtocolError::Missing)
+        );
+
+        // A header from any other peer is not trusted, nor consumed
+        let elsewhere =
+            ProxyProtocolListener::new(ProxyProtocolMode::Required, &["10.0.0.0/8".to_string()])
+                .unwrap();
+        let (mut stream, peer) = connection(&[HEADER]).await;
+        assert_eq!(elsewhere.accept(&mut stream, peer).await, Ok(None));
+        assert_eq!(remaining(stream).await, HEADER);
+    }
+
+    #[test]
+    fn sources_required() {
+        for mode in [ProxyProtocolMode::Optional, ProxyProtocolMode::Required] {
+            assert!(ProxyProtocolListener::new(mode, &[]).is_err());
+        }
+        assert!(ProxyProtocolListener::new(ProxyProtocolMode::Off, &[]).is_ok());
+        assert!(ProxyProtocolListener::new(
+            ProxyProtocolMode::Optional,
+            &["10.0.0.0/33".to_string()]
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn version_1() {
+        let mut data: &[u8] = b"PROXY TCP4 192.0.2.7 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            read_header(&mut data).await,
+            Ok(Some(addrs("192.0.2.7:56324", "198.51.100.1:443")))
+        );
+        // The request itself is left in the stream
+        assert_eq!(data, b"GET / HTTP/1.1\r\n");
+
+        let mut data: &[u8] = b"PROXY TCP6 2001:db8::7 2001:db8::1 56324 443\r\n";
+        assert_eq!(
+            read_header(&mut data).await,
+            Ok(Some(addrs("[2001:db8::7]:56324", "[2001:db8::1]:443")))
+        );
+
+        let mut data: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut data).await, Ok(None));
+
+        for line in [
+            &b"PROXY TCP4 2001:db8::7 198.51.100.1 56324 443\r\n"[..],
+            b"PROXY TCP4 192.0.2.7 198.51.100.1 56324\r\n",
+            b"PROXY TCP4 192.0.2.7 198.51.100.1 56324 443 1\r\n",
+        ] {
+            let mut data = line;
+            assert!(matches!(
+                read_header(&mut data).await,
+                Err(ProxyProtocolError::Malformed(_))
+            ));
+        }
+
+        let mut data: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert_eq!(
+            read_header(&mut data).await,
+            Err(ProxyProtocolError::Missing)
+        );
+    }
+
+    #[tokio::test]
+    async fn version_2() {
+        for addrs in [
+            addrs("192.0.2.7:56324", "198.51.100.1:443"),
+            addrs("[2001:db8::7]:56324", "[2001:db8::1]:443"),
+        ] {
+            let header = encode(ProxyProtocolVersion::V2, &addrs);
+            assert_eq!(read_header(&mut header.as_slice()).await, Ok(Some(addrs)));
+
+            let header = encode(ProxyProtocolVersion::V1, &addrs);
+            assert_eq!(read_header(&mut header.as_slice()).await, Ok(Some(addrs)));
+        }
+
+        // Mixed families are sent as IPv6
+        let header = encode(
+            ProxyProtocolVersion::V1,
+            &addrs("192.0.2.7:56324", "[2001:db8::1]:443"),
+        );
+        assert_eq!(
+            header,
+            b"PROXY TCP6 ::ffff:192.0.2.7 2001:db8::1 56324 443\r\n"
+        );
+
+        // LOCAL command with a TLV
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x03\x04\x00\x00".to_vec();
+        header.extend(b"GET");
+        let mut data = header.as_slice();
+        assert_eq!(read_header(&mut data).await, Ok(None));
+        assert_eq!(data, b"GET");
+
+        // Addresses announced longer than the header
+        let mut data: &[u8] = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x04\xc0\x00\x02\x07";
+        assert!(matches!(
+            read_header(&mut data).await,
+            Err(ProxyProtocolError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,184 @@
+//! Serialization of the geo enrichment as a single header, either an RFC 8941 structured field
+//! dictionary, an RFC 9651 one, or a base64 encoded JSON object.
+
+use std::fmt::Write;
+
+use serde_json::{Map, Number, Value};
+
+use crate::geo::field::GeoValue;
+use crate::proxy::encoding::HeaderEncoding;
+use crate::proxy::headers::GeoHeader;
+
+const MAX_INTEGER: i64 = 999_999_999_999_999;
+const MAX_DECIMAL: f64 = 999_999_999_999.999;
+
+/// Serializes `entries` as an RFC 8941 dictionary (`city_name="Montreal", accuracy_radius=20`).
+/// sf-strings only hold printable ASCII, other strings are written following `encoding`, an
+/// encoded header becoming a `<key>-encoded` member.
+pub fn to_dictionary<'a>(
+    entries: impl IntoIterator<Item = &'a GeoHeader>,
+    encoding: HeaderEncoding,
+) -> String {
+    let members = entries.into_iter().flat_map(|entry| match entry.value {
+        GeoValue::String(ref s) => encoding
+            .encode(entry.key, s)
+            .into_iter()
+            .map(|(key, value)| (key.to_ascii_lowercase(), GeoValue::String(value)))
+            .collect(),
+        ref value => vec![(entry.key.to_string(), value.clone())],
+    });
+    write_dictionary(members, false)
+}
+
+/// Serializes `entries` as an RFC 9651 dictionary, strings that are not printable ASCII being
+/// display strings (`city_name=%"Montr%c3%a9al"`). RFC 8941 parsers reject such dictionaries.
+pub fn to_display_dictionary<'a>(entries: impl IntoIterator<Item = &'a GeoHeader>) -> String {
+    write_dictionary(
+        entries
+            .into_iter()
+            .map(|entry| (entry.key.to_string(), entry.value.clone())),
+        true,
+    )
+}
+
+fn write_dictionary(members: impl Iterator<Item = (String, GeoValue)>, display: bool) -> String {
+    let mut out = String::new();
+    for (key, value) in members {
+        if !out.is_empty() {
+            out.push_str(", ");
+        }
+
+        out.push_str(&key);
+        match value {
+            // A true boolean is serialized as a bare key
+            GeoValue::Boolean(true) => {}
+            GeoValue::Boolean(false) => out.push_str("=?0"),
+            ref value => {
+                out.push('=');
+                write_bare_item(&mut out, value, display);
+            }
+        }
+    }
+
+    out
+}
+
+fn write_bare_item(out: &mut String, value: &GeoValue, display: bool) {
+    match value {
+        GeoValue::Integer(i) if i.abs() <= MAX_INTEGER => {
+            let _ = write!(out, "{}", i);
+        }
+        GeoValue::Decimal(d) if d.is_finite() && d.abs() <= MAX_DECIMAL => {
+            // Decimals carry at most three fractional digits and at least one
+            let decimal = format!("{:.3}", d);
+            let decimal = decimal.trim_end_matches('0');
+            out.push_str(decimal);
+            if decimal.ends_with('.') {
+                out.push('0');
+            }
+        }
+        GeoValue::Boolean(b) => out.push_str(if *b { "?1" } else { "?0" }),
+        GeoValue::String(s) => write_string(out, s, display),
+        // Numbers out of the structured field ranges are kept as strings
+        other => write_string(out, &other.to_string(), display),
+    }
+}
+
+/// Writes an sf-string, or an RFC 9651 display string when `s` is not printable ASCII and
+/// `display` is set. Otherwise the characters an sf-string cannot hold are dropped.
+fn write_string(out: &mut String, s: &str, display: bool) {
+    let printable = |b: u8| (0x20..=0x7e).contains(&b);
+    if display && !s.bytes().all(printable) {
+        out.push_str("%\"");
+        for b in s.bytes() {
+            if b == b'%' || b == b'"' || !printable(b) {
+                let _ = write!(out, "%{:02x}", b);
+            } else {
+                out.push(b as char);
+            }
+        }
+        out.push('"');
+    } else {
+        out.push('"');
+        for c in s.chars().filter(|c| c.is_ascii() && printable(*c as u8)) {
+            if c == '"' || c == '\\' {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+        out.push('"');
+    }
+}
+
+/// Serializes `entries` as a JSON object, base64 encoded so it is always a valid header value
+pub fn to_base64_json<'a>(entries: impl IntoIterator<Item = &'a GeoHeader>) -> String {
+    let object = entries
+        .into_iter()
+        .map(|entry| {
+            let value = match entry.value {
+                GeoValue::String(ref s) => Value::String(s.clone()),
+                GeoValue::Integer(i) => Value::Number(i.into()),
+                GeoValue::Decimal(d) => Number::from_f64(d).map_or(Value::Null, Value::Number),
+                GeoValue::Boolean(b) => Value::Bool(b),
+            };
+            (entry.key.to_string(), value)
+        })
+        .collect::<Map<_, _>>();
+
+    base64::encode(Value::Object(object).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_base64_json, to_dictionary, to_display_dictionary};
+    use crate::geo::field::GeoValue;
+    use crate::proxy::encoding::HeaderEncoding;
+    use crate::proxy::headers::GeoHeader;
+
+    fn entries() -> Vec<GeoHeader> {
+        vec![
+            GeoHeader::new(
+                "city_name",
+                "Prux-City",
+                GeoValue::String("Montréal".to_string()),
+            ),
+            GeoHeader::new(
+                "isp",
+                "Prux-ISP",
+                GeoValue::String("Say \"hi\"".to_string()),
+            ),
+            GeoHeader::new("latitude", "X-Lat", GeoValue::Decimal(45.5063)),
+            GeoHeader::new("longitude", "X-Long", GeoValue::Decimal(-73.0)),
+            GeoHeader::new(
+                "accuracy_radius",
+                "Prux-Coord-Accuracy",
+                GeoValue::Integer(20),
+            ),
+            GeoHeader::new("is_in_european_union", "X-EU", GeoValue::Boolean(true)),
+        ]
+    }
+
+    #[test]
+    fn dictionary() {
+        assert_eq!(
+            to_dictionary(&entries(), HeaderEncoding::Transliterate),
+            r#"city_name="Montreal", isp="Say \"hi\"", latitude=45.506, longitude=-73.0, accuracy_radius=20, is_in_european_union"#
+        );
+        assert!(
+            to_dictionary(&entries(), HeaderEncoding::EncodedHeader).starts_with(
+                r#"city_name="Montreal", city_name-encoded="UTF-8''Montr%C3%A9al", isp="#
+            )
+        );
+        assert!(to_display_dictionary(&entries()).starts_with(r#"city_name=%"Montr%c3%a9al", "#));
+    }
+
+    #[test]
+    fn base64_json() {
+        let decoded = base64::decode(to_base64_json(&entries())).unwrap();
+        let value = serde_json::from_slice::<serde_json::Value>(&decoded).unwrap();
+        assert_eq!(value["city_name"], "Montréal");
+        assert_eq!(value["latitude"], 45.5063);
+        assert_eq!(value["accuracy_radius"], 20);
+        assert_eq!(value["is_in_european_union"], true);
+    }
+}
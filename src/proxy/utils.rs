@@ -1,25 +1,22 @@
-use hyper::client::HttpConnector;
-use hyper::header::{HeaderName, HeaderValue};
-use hyper::{Body, Client, HeaderMap, Request, Response, Uri};
-use hyper_tls::HttpsConnector;
-use log::error;
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{Body, HeaderMap, Request, Response, Uri};
+use ipnetwork::IpNetwork;
+use log::{debug, error, warn};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
+use std::sync::Arc;
 
-use crate::IpResolver;
+use crate::geo::field::GeoValue;
+use crate::geo::{GeoError, GeoResolver, ServiceTier};
+use crate::proxy::classify::canonical_ip;
+use crate::proxy::client_ip::ClientIpSources;
+use crate::proxy::headers::{GeoHeader, GeoHeaders, HeaderMapping};
+use crate::proxy::UpstreamClient;
+use prux::signature::unix_time;
 
 const PRUX_ADDR: &str = "Prux-Addr";
-const PRUX_CITY: &str = "Prux-City";
-const PRUX_COUNTRY: &str = "Prux-Country";
-const PRUX_PROVINCE: &str = "Prux-Province";
-const PRUX_COORD: &str = "Prux-Coord";
-const PRUX_COORD_ACCURACY: &str = "Prux-Coord-Accuracy";
-const PRUX_TIMEZONE: &str = "Prux-Timezone";
-const PRUX_ISP: &str = "Prux-ISP";
-const PRUX_NETWORK: &str = "Prux-Network";
-// lat / long
-static IPV6_FORWARDED_TRIM_VALUE: &[char] = &['"', '[', ']'];
+const PRUX_GEO_STATUS: &str = "Prux-Geo-Status";
+const PRUX_PORT: &str = "Prux-Port";
 
 #[derive(Debug)]
 pub struct StringError(pub String);
@@ -32,75 +29,61 @@ impl std::fmt::Display for StringError {
 
 impl std::error::Error for StringError {}
 
-pub async fn add_ip_hdr(ip: &IpAddr, hdr_map: &mut HashMap<String, String>) {
-    hdr_map.insert(PRUX_ADDR.to_string(), ip.to_string());
+pub async fn add_ip_hdr(ip: &IpAddr, hdr_map: &mut GeoHeaders) {
+    hdr_map.insert(GeoHeader::new(
+        "addr",
+        PRUX_ADDR,
+        GeoValue::String(ip.to_string()),
+    ));
 }
 
-pub async fn get_location_hdr(
-    ip: IpAddr,
-    resolver: IpResolver,
-    hdr_map: &mut HashMap<String, String>,
-) -> Result<(), ()> {
-    let json = resolver.lookup(&ip).await?;
-
-    if let Some(Some(city_name_en)) = json
-        .get("city")
-        .and_then(|val| val.get("names"))
-        .and_then(|names| names.get("en").map(|en_name| en_name.as_str()))
-    {
-        hdr_map.insert(PRUX_CITY.to_string(), city_name_en.to_string());
-    }
-
-    if let Some(Some(country_name_en)) = json
-        .get("country")
-        .and_then(|val| val.get("names"))
-        .and_then(|names| names.get("en").map(|en_name| en_name.as_str()))
-    {
-        hdr_map.insert(PRUX_COUNTRY.to_string(), country_name_en.to_string());
-    }
-
-    if let Some(loc) = json.get("location") {
-        if let (Some(lat), Some(long)) = (
-            loc.get("latitude").and_then(|l| l.as_f64()),
-            loc.get("longitude").and_then(|l| l.as_f64()),
-        ) {
-            hdr_map.insert(PRUX_COORD.to_string(), format!("{},{}", lat, long));
-        }
+pub fn add_port_hdr(port: u16, hdr_map: &mut GeoHeaders) {
+    hdr_map.insert(GeoHeader::new(
+        "port",
+        PRUX_PORT,
+        GeoValue::Integer(i64::from(port)),
+    ));
+}
 
-        if let Some(acc) = loc.get("accuracy_radius").and_then(|acc| acc.as_f64()) {
-            hdr_map.insert(PRUX_COORD_ACCURACY.to_string(), format!("{acc:.0}"));
-        }
+/// Marks a request forwarded without geo headers because its lookup failed
+pub fn add_geo_status_hdr(error: &GeoError, hdr_map: &mut GeoHeaders) {
+    let status = if error.is_permanent() {
+        "unknown"
+    } else {
+        "unavailable"
+    };
+    hdr_map.insert(GeoHeader::new(
+        "geo_status",
+        PRUX_GEO_STATUS,
+        GeoValue::String(status.to_string()),
+    ));
+}
 
-        if let Some(loc) = loc.get("time_zone").and_then(|tz| tz.as_str()) {
-            hdr_map.insert(PRUX_TIMEZONE.to_string(), loc.to_string());
-        }
-    }
+pub async fn get_location_hdr(
+    ip: IpAddr,
+    tier: ServiceTier,
+    resolver: Arc<dyn GeoResolver>,
+    mapping: &HeaderMapping,
+    hdr_map: &mut GeoHeaders,
+) -> Result<(), GeoError> {
+    let lookup = resolver.lookup(&ip, tier).await?;
+    debug!(
+        "Resolved {} with {} (cached: {})",
+        ip, lookup.source, lookup.cached
+    );
 
-    if let Some(iso) = json
-        .get("subdivisions")
-        .and_then(|sub| sub.get("iso_code"))
-        .and_then(|iso| iso.as_str())
-    {
-        hdr_map.insert(PRUX_PROVINCE.to_string(), iso.to_string());
+    for header in mapping.values(&lookup.record) {
+        hdr_map.insert(header);
     }
 
-    if let Some(traits) = json.get("traits") {
-        if let Some(isp) = traits.get("isp").and_then(|isp| isp.as_str()) {
-            hdr_map.insert(PRUX_ISP.to_string(), isp.to_string());
-        }
-
-        if let Some(network) = traits.get("network").and_then(|network| network.as_str()) {
-            hdr_map.insert(PRUX_NETWORK.to_string(), network.to_string());
-        }
+    if let Some(sources) = mapping.sources(&lookup) {
+        hdr_map.insert(sources);
     }
 
     Ok(())
 }
 
-pub async fn gen_transmit_fut(
-    client: &Client<HttpsConnector<HttpConnector>>,
-    req: Request<Body>,
-) -> Response<Body> {
+pub async fn gen_transmit_fut(client: &UpstreamClient, req: Request<Body>) -> Response<Body> {
     match client.request(req).await {
         Ok(response) => response,
         Err(e) => {
@@ -119,110 +102,422 @@ pub async fn gen_transmit_fut(
 pub fn construct_request(
     request: Request<Body>,
     new_uri: Uri,
-    headers: Option<HashMap<String, String>>,
+    headers: Option<GeoHeaders>,
+    mapping: &HeaderMapping,
 ) -> Request<Body> {
     let mut request = request;
     *request.uri_mut() = new_uri;
 
-    if let Some(map) = headers {
-        for (header, value) in map {
-            request.headers_mut().insert(
-                HeaderName::from_bytes(header.as_bytes()).expect("should be ok"),
-                HeaderValue::from_str(value.as_str()).expect("should be ok"),
-            );
+    // Headers prux manages are never taken from the client, even when it adds none of its own
+    let spoofed = request
+        .headers()
+        .keys()
+        .filter(|name| mapping.is_managed(name))
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in spoofed {
+        debug!("Removing client supplied {} header", name);
+        request.headers_mut().remove(name);
+    }
+
+    if let Some(headers) = headers {
+        let mut added = Vec::new();
+        for (header, value) in mapping.render(&headers) {
+            match (
+                HeaderName::from_bytes(header.as_bytes()),
+                HeaderValue::from_str(&value),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    request.headers_mut().insert(name.clone(), value);
+                    added.push(name);
+                }
+                _ => warn!(
+                    "Dropping geo header {} with invalid value {:?}",
+                    header, value
+                ),
+            }
+        }
+
+        if let (Some(signer), false) = (&mapping.signer, added.is_empty()) {
+            let path = request
+                .uri()
+                .path_and_query()
+                .map_or("/", |path| path.as_str());
+            let signature = signer.sign(request.headers(), &added, path, unix_time());
+            request
+                .headers_mut()
+                .insert(signer.header.clone(), signature);
         }
     }
 
     request
 }
 
-pub fn ip_is_global(ip: &IpAddr) -> bool {
-    match ip {
-        IpAddr::V4(ip) => {
-            !ip.is_private()
-                && !ip.is_loopback()
-                && !ip.is_link_local()
-                && !ip.is_broadcast()
-                && !ip.is_documentation()
-                && !ip.is_unspecified()
-        }
-        IpAddr::V6(ip) => !ip.is_loopback() && !ip.is_unspecified(),
+/// Proxies allowed to report the client address in forwarding headers
+#[derive(Debug, Default)]
+pub struct TrustedProxies(Vec<IpNetwork>);
+
+impl TrustedProxies {
+    pub fn new(networks: &[String]) -> Result<Self, String> {
+        Ok(TrustedProxies(
+            networks
+                .iter()
+                .map(|cidr| {
+                    IpNetwork::from_str(cidr.trim())
+                        .map_err(|e| format!("Invalid trusted proxy {}: {}", cidr, e))
+                })
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = canonical_ip(*ip);
+        self.0.iter().any(|network| network.contains(ip))
     }
 }
 
+/// Client address along with its port, when known
+pub type ClientAddr = (IpAddr, Option<u16>);
+
+/// Client address of `req`, read from the first of `sources` present in the request
 pub fn get_forwarded_ip(
     req: &Request<Body>,
-    forwarded_ip_header: Option<&str>,
-    use_forwarded_ip_header_only: bool,
-) -> Option<IpAddr> {
-    get_forwarded_ip_from_headers(
-        req.headers(),
-        forwarded_ip_header,
-        use_forwarded_ip_header_only,
-    )
+    sources: &ClientIpSources,
+    source_ip: Option<IpAddr>,
+) -> Option<ClientAddr> {
+    sources.client_addr(req.headers(), source_ip)
 }
 
-fn get_forwarded_ip_from_headers(
-    headers: &HeaderMap,
-    forwarded_ip_header: Option<&str>,
-    use_forwarded_ip_header_only: bool,
-) -> Option<IpAddr> {
-    let mut ip_str = forwarded_ip_header.and_then(|header| {
-        headers
-            .get(header)
-            .map(|value| String::from_utf8_lossy(value.as_bytes()))
-            .map(|str_val| str_val.trim().to_lowercase())
-    });
-
-    if !use_forwarded_ip_header_only {
-        ip_str = ip_str
-            .or_else(|| get_ip_str_from_x_forwarded_header(headers))
-            .or_else(|| get_ip_str_from_forwarded_header(headers));
-    }
-
-    ip_str.and_then(|ip_str| {
-        Ipv4Addr::from_str(&ip_str)
-            .map(IpAddr::V4)
-            .ok()
-            .or_else(|| Ipv6Addr::from_str(&ip_str).map(IpAddr::V6).ok())
-    })
+pub fn parse_ip(ip_str: &str) -> Option<IpAddr> {
+    Ipv4Addr::from_str(ip_str)
+        .map(IpAddr::V4)
+        .ok()
+        .or_else(|| Ipv6Addr::from_str(ip_str).map(IpAddr::V6).ok())
 }
 
-fn get_ip_str_from_x_forwarded_header(headers: &HeaderMap) -> Option<String> {
-    headers
-        .get("X-Forwarded-For")
-        .map(|value| String::from_utf8_lossy(value.as_bytes()))
-        .map(|str_val| {
-            str_val
-                .split_once(',')
-                .map_or(&*str_val, |s| s.0)
-                .trim()
-                .trim_matches(IPV6_FORWARDED_TRIM_VALUE)
-                .to_lowercase()
+/// Address and port of a forwarding header value, such as `192.0.2.1`, `192.0.2.1:51234`,
+/// `2001:db8::1`, `[2001:db8::1]` or `"[2001:db8::1]:443"`
+pub fn parse_ip_port(value: &str) -> Option<ClientAddr> {
+    let value = value.trim().trim_matches('"');
+    parse_ip(value)
+        .map(|ip| (ip, None))
+        .or_else(|| {
+            SocketAddr::from_str(value)
+                .ok()
+                .map(|addr| (addr.ip(), Some(addr.port())))
+        })
+        .or_else(|| {
+            value
+                .strip_prefix('[')
+                .and_then(|v| v.strip_suffix(']'))
+                .and_then(parse_ip)
+                .map(|ip| (ip, None))
         })
 }
 
-fn get_ip_str_from_forwarded_header(headers: &HeaderMap) -> Option<String> {
-    headers
-        .get("Forwarded")
-        .map(|value| String::from_utf8_lossy(value.as_bytes()))
-        .and_then(|str_val| {
-            str_val.to_lowercase().split(';').find_map(|s| {
-                s.split_once("for=")
-                    .map(|s| s.1)
-                    .map(|s| s.split_once(',').map_or(s, |s| s.0).trim())
-                    .map(|s| s.trim_matches(IPV6_FORWARDED_TRIM_VALUE).to_string())
-            })
+/// Client address among `hops`, ordered from the client to the last proxy. Hops without a
+/// usable address are `None`. Without trusted proxies the left-most hop is taken as is,
+/// otherwise the hops are walked from the right, the client being the first untrusted one.
+pub fn client_hop(
+    hops: &[Option<ClientAddr>],
+    trusted_proxies: &TrustedProxies,
+) -> Option<ClientAddr> {
+    if trusted_proxies.is_empty() {
+        return hops.first().copied().flatten();
+    }
+
+    let mut client = None;
+    for hop in hops.iter().rev() {
+        // Hops left of an unreadable one were not written by a trusted proxy
+        let addr = (*hop)?;
+        if !trusted_proxies.contains(&addr.0) {
+            return Some(addr);
+        }
+        // Every hop being trusted, the left-most one is the client
+        client = Some(addr);
+    }
+    client
+}
+
+/// Addresses of every X-Forwarded-For header, from the client to the last proxy
+pub fn get_ips_from_x_forwarded_header(headers: &HeaderMap) -> Option<Vec<Option<ClientAddr>>> {
+    let hops = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .flat_map(|value| {
+            String::from_utf8_lossy(value.as_bytes())
+                .split(',')
+                .map(parse_ip_port)
+                .collect::<Vec<_>>()
         })
+        .collect::<Vec<_>>();
+
+    (!hops.is_empty()).then_some(hops)
+}
+
+/// `for` addresses of every Forwarded header, from the client to the last proxy. Elements
+/// without a `for` parameter are skipped, unknown and obfuscated nodes are `None`.
+pub fn get_ips_from_forwarded_header(headers: &HeaderMap) -> Option<Vec<Option<ClientAddr>>> {
+    let elements = parse_forwarded(headers.get_all(header::FORWARDED))
+        .map_err(|e| debug!("Ignoring Forwarded header: {}", e))
+        .ok()?;
+    let hops = elements
+        .into_iter()
+        .filter_map(|element| element.for_node)
+        .map(|node| node.ip().map(|ip| (ip, node.port())))
+        .collect::<Vec<_>>();
+
+    (!hops.is_empty()).then_some(hops)
+}
+
+/// Identifier of a `by` or `for` node (RFC 7239 section 6)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeName {
+    Ip(IpAddr),
+    /// The proxy does not know the previous node, `unknown`
+    Unknown,
+    /// Identifier hiding the real address, such as `_hidden`
+    Obfuscated(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodePort {
+    Port(u16),
+    Obfuscated(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardedNode {
+    pub name: NodeName,
+    pub port: Option<NodePort>,
+}
+
+impl ForwardedNode {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self.name {
+            NodeName::Ip(ip) => Some(ip),
+            _ => None,
+        }
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        match self.port {
+            Some(NodePort::Port(port)) => Some(port),
+            _ => None,
+        }
+    }
+}
+
+fn is_obfuscated(s: &str) -> bool {
+    s.len() > 1
+        && s.starts_with('_')
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+impl FromStr for ForwardedNode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid forwarded node: {}", s);
+
+        let (name, port) = if let Some(rest) = s.strip_prefix('[') {
+            let (ip, port) = rest.split_once(']').ok_or_else(invalid)?;
+            let port = match port {
+                "" => None,
+                port => Some(port.strip_prefix(':').ok_or_else(invalid)?),
+            };
+            (
+                NodeName::Ip(IpAddr::V6(Ipv6Addr::from_str(ip).map_err(|_| invalid())?)),
+                port,
+            )
+        } else if let Ok(ip) = Ipv6Addr::from_str(s) {
+            // Not allowed by the grammar, but sent by some proxies
+            (NodeName::Ip(IpAddr::V6(ip)), None)
+        } else {
+            let (name, port) = s.split_once(':').map_or((s, None), |(n, p)| (n, Some(p)));
+            let name = if name.eq_ignore_ascii_case("unknown") {
+                NodeName::Unknown
+            } else if is_obfuscated(name) {
+                NodeName::Obfuscated(name.to_string())
+            } else {
+                NodeName::Ip(IpAddr::V4(Ipv4Addr::from_str(name).map_err(|_| invalid())?))
+            };
+            (name, port)
+        };
+
+        let port = port
+            .map(|port| {
+                if is_obfuscated(port) {
+                    Ok(NodePort::Obfuscated(port.to_string()))
+                } else if (1..=5).contains(&port.len()) && port.bytes().all(|b| b.is_ascii_digit())
+                {
+                    port.parse().map(NodePort::Port).map_err(|_| invalid())
+                } else {
+                    Err(invalid())
+                }
+            })
+            .transpose()?;
+
+        Ok(ForwardedNode { name, port })
+    }
+}
+
+/// One hop of a Forwarded header (RFC 7239 section 4)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardedElement {
+    /// Interface where the request came in to the proxy
+    pub by_node: Option<ForwardedNode>,
+    /// Node making the request to the proxy
+    pub for_node: Option<ForwardedNode>,
+    /// Host request header as received by the proxy
+    pub host: Option<String>,
+    /// Protocol used to make the request
+    pub proto: Option<String>,
+    /// Parameters not defined by the RFC, names being lowercase
+    pub extensions: Vec<(String, String)>,
+}
+
+impl ForwardedElement {
+    fn set(&mut self, name: &str, value: String) -> Result<(), String> {
+        let name = name.to_ascii_lowercase();
+        let duplicate = match name.as_str() {
+            "by" => self.by_node.replace(value.parse()?).is_some(),
+            "for" => self.for_node.replace(value.parse()?).is_some(),
+            "host" => self.host.replace(value).is_some(),
+            "proto" => self.proto.replace(value.to_ascii_lowercase()).is_some(),
+            _ => {
+                let duplicate = self.extensions.iter().any(|(n, _)| *n == name);
+                self.extensions.push((name.clone(), value));
+                duplicate
+            }
+        };
+
+        if duplicate {
+            return Err(format!("Duplicate forwarded parameter: {}", name));
+        }
+        Ok(())
+    }
+}
+
+/// Token characters (RFC 7230 section 3.2.6)
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// Splits the quoted string at the start of `s` from what follows it
+fn quoted_string(s: &str) -> Result<(String, &str), String> {
+    let mut value = String::new();
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &s[i + 1..])),
+            '\\' => value.push(
+                chars
+                    .next()
+                    .map(|(_, c)| c)
+                    .ok_or_else(|| "Unterminated quoted string".to_string())?,
+            ),
+            c => value.push(c),
+        }
+    }
+    Err("Unterminated quoted string".to_string())
+}
+
+/// Parses the forwarded elements of every Forwarded header `values`, from the client to the last
+/// proxy. Unquoted values are accepted up to the next delimiter so that IPv6 nodes sent without
+/// quotes are still understood.
+pub fn parse_forwarded<'a>(
+    values: impl IntoIterator<Item = &'a HeaderValue>,
+) -> Result<Vec<ForwardedElement>, String> {
+    let mut elements = Vec::new();
+
+    for value in values {
+        let mut rest = value
+            .to_str()
+            .map_err(|_| "Forwarded header is not valid ASCII".to_string())?;
+        let mut element = ForwardedElement::default();
+
+        loop {
+            rest = rest.trim_start_matches([' ', '\t']);
+            if rest.is_empty() || rest.starts_with(',') {
+                // Empty list elements are allowed and ignored
+                if element != ForwardedElement::default() {
+                    elements.push(std::mem::take(&mut element));
+                }
+                match rest.strip_prefix(',') {
+                    Some(next) => {
+                        rest = next;
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+            if let Some(next) = rest.strip_prefix(';') {
+                rest = next;
+                continue;
+            }
+
+            let name_len = rest.find(|c| !is_tchar(c)).unwrap_or(rest.len());
+            let (name, next) = rest.split_at(name_len);
+            let next = next
+                .strip_prefix('=')
+                .filter(|_| !name.is_empty())
+                .ok_or_else(|| format!("Invalid forwarded pair: {}", rest))?;
+
+            let (value, next) = if next.starts_with('"') {
+                quoted_string(next)?
+            } else {
+                let len = next.find([';', ',', ' ', '\t']).unwrap_or(next.len());
+                (next[..len].to_string(), &next[len..])
+            };
+            if value.is_empty() {
+                return Err(format!("Empty forwarded parameter: {}", name));
+            }
+
+            element.set(name, value)?;
+            rest = next;
+        }
+    }
+
+    Ok(elements)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::get_forwarded_ip_from_headers;
+    use super::{
+        construct_request, get_location_hdr, parse_forwarded, ForwardedElement, ForwardedNode,
+        NodeName, NodePort, TrustedProxies,
+    };
+    use crate::geo::field::GeoValue;
+    use crate::geo::testing::FixedResolver;
+    use crate::geo::ServiceTier;
+    use crate::proxy::client_ip::ClientIpSources;
+    use crate::proxy::encoding::HeaderEncoding;
+    use crate::proxy::headers::{GeoHeader, GeoHeaders, HeaderMapping};
     use hyper::header::{HeaderName, HeaderValue};
     use hyper::{header, HeaderMap};
+    use hyper::{Body, Request, Uri};
+    use prux::signature::{unix_time, Signer};
+    use serde_json::json;
+    use std::collections::HashMap;
     use std::net::IpAddr;
     use std::str::FromStr;
+    use std::time::Duration;
+
+    fn sources(header: Option<&str>, header_only: bool, trusted: &[&str]) -> ClientIpSources {
+        ClientIpSources::new(
+            ClientIpSources::from_header(header, header_only).unwrap(),
+            TrustedProxies::new(&trusted.iter().map(|t| t.to_string()).collect::<Vec<_>>())
+                .unwrap(),
+            &HashMap::new(),
+        )
+        .unwrap()
+    }
 
     fn build_test_header(forwarded: Option<&str>, x_forwarded: Option<&str>) -> HeaderMap {
         let mut headers = HeaderMap::with_capacity(2);
@@ -246,7 +541,9 @@ mod tests {
         let forwarded = "for=192.0.2.43";
         let headers = build_test_header(Some(forwarded), None);
         assert_eq!(
-            get_forwarded_ip_from_headers(&headers, None, false),
+            sources(None, false, &[])
+                .client_addr(&headers, None)
+                .map(|(ip, _)| ip),
             IpAddr::from_str("192.0.2.43").ok(),
             r#"testing simple ipv4 Forwarded header : "Fowrarded: {}""#,
             forwarded
@@ -255,7 +552,9 @@ mod tests {
         let forwarded = r#"for="[2001:db8:cafe::17]""#;
         let headers = build_test_header(Some(forwarded), None);
         assert_eq!(
-            get_forwarded_ip_from_headers(&headers, None, false),
+            sources(None, false, &[])
+                .client_addr(&headers, None)
+                .map(|(ip, _)| ip),
             IpAddr::from_str("2001:db8:cafe::17").ok(),
             r#"testing simple ipv6 Forwarded header : "Fowrarded: {}""#,
             forwarded
@@ -264,7 +563,9 @@ mod tests {
         let forwarded = r#"for=192.0.2.44, for="[2001:db8:cafe::17]""#;
         let headers = build_test_header(Some(forwarded), None);
         assert_eq!(
-            get_forwarded_ip_from_headers(&headers, None, false),
+            sources(None, false, &[])
+                .client_addr(&headers, None)
+                .map(|(ip, _)| ip),
             IpAddr::from_str("192.0.2.44").ok(),
             r#"testing Forwarded header with multiple for : "Fowrarded: {}""#,
             forwarded
@@ -273,7 +574,9 @@ mod tests {
         let forwarded = r#"for=192.0.2.45  ,  for="[2001:db8:cafe::17]""#;
         let headers = build_test_header(Some(forwarded), None);
         assert_eq!(
-            get_forwarded_ip_from_headers(&headers, None, false),
+            sources(None, false, &[])
+                .client_addr(&headers, None)
+                .map(|(ip, _)| ip),
             IpAddr::from_str("192.0.2.45").ok(),
             r#"testing Forwarded header with multiple for and whitespaces : "Fowrarded: {}""#,
             forwarded
@@ -282,19 +585,166 @@ mod tests {
         let forwarded = r#"by=203.0.113.42;for=192.0.2.46, for="[2001:db8:cafe::17]""#;
         let headers = build_test_header(Some(forwarded), None);
         assert_eq!(
-            get_forwarded_ip_from_headers(&headers, None, false),
+            sources(None, false, &[])
+                .client_addr(&headers, None)
+                .map(|(ip, _)| ip),
             IpAddr::from_str("192.0.2.46").ok(),
             r#"testing Forwarded header "by" clause : "Fowrarded: {}""#,
             forwarded
         );
     }
 
+    fn node(name: NodeName, port: Option<NodePort>) -> Option<ForwardedNode> {
+        Some(ForwardedNode { name, port })
+    }
+
+    fn ip_node(ip: &str, port: Option<u16>) -> Option<ForwardedNode> {
+        node(
+            NodeName::Ip(IpAddr::from_str(ip).unwrap()),
+            port.map(NodePort::Port),
+        )
+    }
+
+    fn parse(values: &[&'static str]) -> Result<Vec<ForwardedElement>, String> {
+        parse_forwarded(
+            values
+                .iter()
+                .map(|v| HeaderValue::from_static(v))
+                .collect::<Vec<_>>()
+                .iter(),
+        )
+    }
+
+    #[test]
+    fn forwarded_rfc_7239_examples() {
+        // Section 4
+        assert_eq!(
+            parse(&[r#"for="_gazonk""#]),
+            Ok(vec![ForwardedElement {
+                for_node: node(NodeName::Obfuscated("_gazonk".to_string()), None),
+                ..Default::default()
+            }])
+        );
+        assert_eq!(
+            parse(&[r#"For="[2001:db8:cafe::17]:4711""#]),
+            Ok(vec![ForwardedElement {
+                for_node: ip_node("2001:db8:cafe::17", Some(4711)),
+                ..Default::default()
+            }])
+        );
+        assert_eq!(
+            parse(&["for=192.0.2.60;proto=http;by=203.0.113.43"]),
+            Ok(vec![ForwardedElement {
+                for_node: ip_node("192.0.2.60", None),
+                by_node: ip_node("203.0.113.43", None),
+                proto: Some("http".to_string()),
+                ..Default::default()
+            }])
+        );
+        assert_eq!(
+            parse(&["for=192.0.2.43, for=198.51.100.17"]),
+            Ok(vec![
+                ForwardedElement {
+                    for_node: ip_node("192.0.2.43", None),
+                    ..Default::default()
+                },
+                ForwardedElement {
+                    for_node: ip_node("198.51.100.17", None),
+                    ..Default::default()
+                }
+            ])
+        );
+
+        // Section 6
+        assert_eq!(
+            parse(&["for=unknown, for=_hidden, for=_SEVKISEK"]),
+            Ok(vec![
+                ForwardedElement {
+                    for_node: node(NodeName::Unknown, None),
+                    ..Default::default()
+                },
+                ForwardedElement {
+                    for_node: node(NodeName::Obfuscated("_hidden".to_string()), None),
+                    ..Default::default()
+                },
+                ForwardedElement {
+                    for_node: node(NodeName::Obfuscated("_SEVKISEK".to_string()), None),
+                    ..Default::default()
+                }
+            ])
+        );
+        assert_eq!(
+            parse(&[r#"for="192.0.2.43:_abc-1"; by="[2001:db8::1]:_port""#]),
+            Ok(vec![ForwardedElement {
+                for_node: node(
+                    NodeName::Ip(IpAddr::from_str("192.0.2.43").unwrap()),
+                    Some(NodePort::Obfuscated("_abc-1".to_string()))
+                ),
+                by_node: node(
+                    NodeName::Ip(IpAddr::from_str("2001:db8::1").unwrap()),
+                    Some(NodePort::Obfuscated("_port".to_string()))
+                ),
+                ..Default::default()
+            }])
+        );
+
+        // Section 7.1, one header split in several instances
+        assert_eq!(
+            parse(&[
+                "for=192.0.2.43",
+                r#"for=198.51.100.17;by=203.0.113.60;proto=http;host="example.com""#
+            ]),
+            parse(&[
+                r#"for=192.0.2.43, for=198.51.100.17;by=203.0.113.60;proto=http;host="example.com""#
+            ])
+        );
+        assert_eq!(
+            parse(&[r#"for=192.0.2.43;host="ex\"ample.com:8080";ext="a,b;c""#])
+                .unwrap()
+                .remove(0),
+            ForwardedElement {
+                for_node: ip_node("192.0.2.43", None),
+                host: Some("ex\"ample.com:8080".to_string()),
+                extensions: vec![("ext".to_string(), "a,b;c".to_string())],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_forwarded() {
+        for value in [
+            "for",
+            "for=",
+            "=192.0.2.43",
+            r#"for="192.0.2.43"#,
+            "for=192.0.2.43;for=192.0.2.44",
+            "for=192.0.2.256",
+            "for=192.0.2.43:123456",
+            r#"for="[2001:db8::1]4711""#,
+            "for=_",
+        ] {
+            assert!(parse(&[value]).is_err(), "{}", value);
+        }
+
+        // Unknown and obfuscated clients cannot be located
+        let headers = build_test_header(Some("for=_hidden, for=198.51.100.17"), None);
+        assert_eq!(
+            sources(None, false, &[])
+                .client_addr(&headers, None)
+                .map(|(ip, _)| ip),
+            None
+        );
+    }
+
     #[test]
     fn x_forwarded_for() {
         let x_forwarded_for = "192.0.2.43";
         let headers = build_test_header(None, Some(x_forwarded_for));
         assert_eq!(
-            get_forwarded_ip_from_headers(&headers, None, false),
+            sources(None, false, &[])
+                .client_addr(&headers, None)
+                .map(|(ip, _)| ip),
             IpAddr::from_str("192.0.2.43").ok(),
             r#"testing simple ipv4 X-Forwarded-For header : "X-Fowrarded-For: {}""#,
             x_forwarded_for
@@ -303,7 +753,9 @@ mod tests {
         let x_forwarded_for = r#"192.0.2.44, "[2001:db8:cafe::17]""#;
         let headers = build_test_header(None, Some(x_forwarded_for));
         assert_eq!(
-            get_forwarded_ip_from_headers(&headers, None, false),
+            sources(None, false, &[])
+                .client_addr(&headers, None)
+                .map(|(ip, _)| ip),
             IpAddr::from_str("192.0.2.44").ok(),
             r#"testing simple ipv4 X-Forwarded-For header with proxies : "X-Fowrarded-For: {}""#,
             x_forwarded_for
@@ -312,7 +764,9 @@ mod tests {
         let x_forwarded_for = r#"2001:db8:cafe::17"#;
         let headers = build_test_header(None, Some(x_forwarded_for));
         assert_eq!(
-            get_forwarded_ip_from_headers(&headers, None, false),
+            sources(None, false, &[])
+                .client_addr(&headers, None)
+                .map(|(ip, _)| ip),
             IpAddr::from_str("2001:db8:cafe::17").ok(),
             r#"testing simple ipv6 X-Forwarded-For header : "X-Fowrarded-For: {}""#,
             x_forwarded_for
@@ -321,7 +775,9 @@ mod tests {
         let x_forwarded_for = r#""[2001:db8:cafe::17]""#;
         let headers = build_test_header(None, Some(x_forwarded_for));
         assert_eq!(
-            get_forwarded_ip_from_headers(&headers, None, false),
+            sources(None, false, &[])
+                .client_addr(&headers, None)
+                .map(|(ip, _)| ip),
             IpAddr::from_str("2001:db8:cafe::17").ok(),
             r#"testing simple ipv6 X-Forwarded-For header with "Forwarded"-style delimiters : "X-Fowrarded-For: {}""#,
             x_forwarded_for
@@ -334,7 +790,7 @@ mod tests {
         let x_forwarded_for = r#"192.0.2.44, "[2001:db8:cafe::17]""#;
         let headers = build_test_header(Some(forwarded), Some(x_forwarded_for));
         assert_eq!(
-            get_forwarded_ip_from_headers(&headers, None, false),
+            sources(None, false, &[]).client_addr(&headers, None).map(|(ip, _)| ip),
             IpAddr::from_str("192.0.2.44").ok(),
             "Testing \"X-Fowrarded-For\" priority over \"Forwarded\"; Headers: \n\"X-Forwarded-For: {}\"\n\"Forwarded: {}\"",
             x_forwarded_for,
@@ -352,9 +808,260 @@ mod tests {
             HeaderValue::from_str(ip).unwrap(),
         );
         assert_eq!(
-            get_forwarded_ip_from_headers(&headers, header_name.as_deref(), false),
+            sources(header_name.as_deref(), false, &[])
+                .client_addr(&headers, None)
+                .map(|(ip, _)| ip),
             IpAddr::from_str(ip).ok(),
             r#"Testing custom forwarded ip header with header name "CF-Connecting-IP""#,
         );
     }
+
+    #[test]
+    fn client_ports() {
+        let none: [&str; 0] = [];
+        for (x_forwarded_for, ip, port) in [
+            ("203.0.113.7:51234", "203.0.113.7", Some(51234)),
+            ("[2001:db8::1]:443, 10.0.0.1", "2001:db8::1", Some(443)),
+            (r#""[2001:db8::1]""#, "2001:db8::1", None),
+            ("2001:db8::1", "2001:db8::1", None),
+        ] {
+            let headers = build_test_header(None, Some(x_forwarded_for));
+            assert_eq!(
+                sources(None, false, &none).client_addr(&headers, None),
+                Some((IpAddr::from_str(ip).unwrap(), port)),
+                "{}",
+                x_forwarded_for
+            );
+        }
+
+        let headers = build_test_header(Some(r#"for="[2001:db8:cafe::17]:4711""#), None);
+        assert_eq!(
+            sources(None, false, &none).client_addr(&headers, None),
+            Some((IpAddr::from_str("2001:db8:cafe::17").unwrap(), Some(4711)))
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Real-IP", HeaderValue::from_static("198.51.100.7:8080"));
+        assert_eq!(
+            sources(Some("X-Real-IP"), true, &none).client_addr(&headers, None),
+            Some((IpAddr::from_str("198.51.100.7").unwrap(), Some(8080)))
+        );
+
+        let headers = build_test_header(None, Some("203.0.113.7:99999"));
+        assert_eq!(
+            sources(None, false, &none).client_addr(&headers, None),
+            None
+        );
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let trusted = ["10.0.0.0/8", "2001:db8::/32"];
+        let proxy = IpAddr::from_str("10.0.0.2").ok();
+
+        // The left-most address is forged by the client
+        let headers = build_test_header(None, Some("198.51.100.7, 203.0.113.9, 10.0.0.1"));
+        assert_eq!(
+            sources(None, false, &trusted)
+                .client_addr(&headers, proxy)
+                .map(|(ip, _)| ip),
+            IpAddr::from_str("203.0.113.9").ok()
+        );
+        assert_eq!(
+            sources(None, false, &[])
+                .client_addr(&headers, proxy)
+                .map(|(ip, _)| ip),
+            IpAddr::from_str("198.51.100.7").ok()
+        );
+
+        // Headers sent straight by a client are ignored
+        let client = IpAddr::from_str("203.0.113.50").ok();
+        assert_eq!(
+            sources(None, false, &trusted)
+                .client_addr(&headers, client)
+                .map(|(ip, _)| ip),
+            None
+        );
+
+        let forwarded = r#"for=198.51.100.7, for="[2001:db8::1]""#;
+        let headers = build_test_header(Some(forwarded), None);
+        assert_eq!(
+            sources(None, false, &trusted)
+                .client_addr(&headers, proxy)
+                .map(|(ip, _)| ip),
+            IpAddr::from_str("198.51.100.7").ok()
+        );
+
+        // Only proxies, the client is the left-most one
+        let headers = build_test_header(None, Some("10.1.1.1, 10.0.0.1"));
+        assert_eq!(
+            sources(None, false, &trusted)
+                .client_addr(&headers, proxy)
+                .map(|(ip, _)| ip),
+            IpAddr::from_str("10.1.1.1").ok()
+        );
+
+        // Nothing left of an unreadable hop can be trusted
+        let headers = build_test_header(None, Some("198.51.100.7, garbage, 10.0.0.1"));
+        assert_eq!(
+            sources(None, false, &trusted)
+                .client_addr(&headers, proxy)
+                .map(|(ip, _)| ip),
+            None
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.append("X-Forwarded-For", HeaderValue::from_static("198.51.100.7"));
+        headers.append("X-Forwarded-For", HeaderValue::from_static("10.0.0.1"));
+        assert_eq!(
+            sources(None, false, &trusted)
+                .client_addr(&headers, proxy)
+                .map(|(ip, _)| ip),
+            IpAddr::from_str("198.51.100.7").ok()
+        );
+
+        assert!(TrustedProxies::new(&["10.0.0.0/40".to_string()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn location_headers_from_resolver() {
+        let resolver = FixedResolver::new(
+            "static",
+            json!({
+            "city": { "names": { "en": "Lavaltrie" } },
+            "country": { "iso_code": "CA", "names": { "en": "Canada", "fr": "Canada" } },
+            "subdivisions": [{ "iso_code": "QC", "names": { "en": "Quebec", "fr": "Québec" } }],
+            "location": {
+                "latitude": 45.88,
+                "longitude": -73.28,
+                "accuracy_radius": 20,
+                "time_zone": "America/Toronto"
+            },
+            "traits": { "isp": "Videotron", "network": "24.200.0.0/14" }
+            }),
+        );
+
+        let mut hdr_map = GeoHeaders::default();
+        get_location_hdr(
+            IpAddr::from_str("24.201.0.1").unwrap(),
+            ServiceTier::City,
+            resolver,
+            &HeaderMapping::new("fr", &HashMap::new()).unwrap(),
+            &mut hdr_map,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(hdr_map.get("Prux-City").as_deref(), Some("Lavaltrie"));
+        assert_eq!(hdr_map.get("Prux-Country").as_deref(), Some("Canada"));
+        assert_eq!(hdr_map.get("Prux-Coord").as_deref(), Some("45.88,-73.28"));
+        assert_eq!(hdr_map.get("Prux-Coord-Accuracy").as_deref(), Some("20"));
+        assert_eq!(
+            hdr_map.get("Prux-Timezone").as_deref(),
+            Some("America/Toronto")
+        );
+        assert_eq!(hdr_map.get("Prux-ISP").as_deref(), Some("Videotron"));
+        assert_eq!(
+            hdr_map.get("Prux-Network").as_deref(),
+            Some("24.200.0.0/14")
+        );
+        assert_eq!(hdr_map.get("Prux-Province").as_deref(), Some("QC"));
+        assert_eq!(hdr_map.get("Prux-Province-Name").as_deref(), Some("Québec"));
+        assert_eq!(
+            hdr_map.get("Prux-Subdivision-Code").as_deref(),
+            Some("CA-QC")
+        );
+    }
+
+    #[test]
+    fn non_ascii_header_values() {
+        let mut headers = GeoHeaders::default();
+        headers.insert(GeoHeader::new(
+            "city_name",
+            "Prux-City",
+            GeoValue::String("São Paulo".to_string()),
+        ));
+        headers.insert(GeoHeader::new(
+            "isp",
+            "Prux-ISP",
+            GeoValue::String("Bad\nISP".to_string()),
+        ));
+        headers.insert(GeoHeader::new(
+            "domain",
+            "Not A Header",
+            GeoValue::String("example.com".to_string()),
+        ));
+
+        let request = construct_request(
+            Request::new(Body::empty()),
+            Uri::from_static("http://upstream/"),
+            Some(headers.clone()),
+            &HeaderMapping::default(),
+        );
+        assert_eq!(request.headers()["Prux-City"], "Sao Paulo");
+        assert_eq!(request.headers()["Prux-ISP"], "BadISP");
+        assert_eq!(request.headers().len(), 2);
+
+        let request = construct_request(
+            Request::new(Body::empty()),
+            Uri::from_static("http://upstream/"),
+            Some(headers),
+            &HeaderMapping::default().with_encoding(HeaderEncoding::Rfc8187),
+        );
+        assert_eq!(request.headers()["Prux-City"], "UTF-8''S%C3%A3o%20Paulo");
+        assert_eq!(request.headers()["Prux-ISP"], "UTF-8''Bad%0AISP");
+    }
+
+    #[test]
+    fn spoofed_headers_removed() {
+        let request = Request::builder()
+            .header("Prux-Country", "Canada")
+            .header("Prux-Addr", "1.1.1.1")
+            .header("X-Custom", "kept")
+            .body(Body::empty())
+            .unwrap();
+
+        let request = construct_request(
+            request,
+            Uri::from_static("http://upstream/"),
+            None,
+            &HeaderMapping::default(),
+        );
+        assert!(request.headers().get("Prux-Country").is_none());
+        assert!(request.headers().get("Prux-Addr").is_none());
+        assert_eq!(request.headers()["X-Custom"], "kept");
+    }
+
+    #[test]
+    fn signed_geo_headers() {
+        let signer = Signer::new(b"0123456789abcdef0123456789abcdef", "Prux-Signature").unwrap();
+        let mapping = HeaderMapping::default().with_signer(Some(signer.clone()));
+        let mut headers = GeoHeaders::default();
+        headers.insert(GeoHeader::new(
+            "city_name",
+            "Prux-City",
+            GeoValue::String("Montreal".to_string()),
+        ));
+
+        let request = Request::builder()
+            .header("Prux-Signature", "forged")
+            .body(Body::empty())
+            .unwrap();
+        let request = construct_request(
+            request,
+            Uri::from_static("http://upstream/login?next=/"),
+            Some(headers),
+            &mapping,
+        );
+
+        assert_eq!(
+            signer.verify(
+                request.headers(),
+                "/login?next=/",
+                unix_time(),
+                Duration::from_secs(5)
+            ),
+            Ok(vec![HeaderName::from_static("prux-city")])
+        );
+    }
 }
@@ -3,10 +3,13 @@
 use clap::{crate_name, crate_version, Arg, Command};
 use config::{Config, ConfigError, Environment, File as ConfigFile};
 use log::LevelFilter;
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::fs::File;
 use std::io::Write;
 
+use crate::geo::overrides::NetworkOverride;
+
 const CONFIGURATION_FILE_NAME: &str = "lucid_conf";
 
 #[derive(Debug)]
@@ -50,24 +53,73 @@ pub struct Server {
     pub uri: String,
     pub maxmind_id: String,
     pub maxmind_password: String,
+    pub maxmind_base_url: String,
+    pub maxmind_api_version: String,
+    pub maxmind_service: String,
+    pub maxmind_connect_timeout_ms: u64,
+    pub maxmind_timeout_ms: u64,
+    pub maxmind_retries: u32,
+    pub maxmind_retry_backoff_ms: u64,
+    pub circuit_breaker_failure_threshold: u32,
+    pub circuit_breaker_cooldown_secs: u64,
+    pub resolver: String,
+    pub mmdb_path: Option<String>,
+    pub mmdb_reload_interval_secs: u64,
     pub maxmind_path_inclusions: String,
+    pub maxmind_country_path_inclusions: Option<String>,
+    pub maxmind_city_path_inclusions: Option<String>,
+    pub maxmind_insights_path_inclusions: Option<String>,
     pub ip_path_inclusions: String,
     pub path_exclusions: Option<String>,
     pub cache_capacity: usize,
     pub cache_duration_secs: u64,
+    pub negative_cache_duration_secs: u64,
+    pub transient_failure_cache_secs: u64,
     pub forwarded_ip_header: Option<String>,
     pub use_forwarded_ip_header_only: bool,
+    pub geo_failure_policy: String,
+    pub geo_fail_open_path_inclusions: Option<String>,
+    pub geo_fail_closed_path_inclusions: Option<String>,
+    pub geo_failure_status: u16,
+    pub geo_failure_content_type: String,
+    pub geo_failure_body: String,
+    pub geo_locale: String,
+    pub geo_header_format: String,
+    pub geo_structured_header: String,
+    pub geo_header_encoding: String,
+    pub scrub_headers: Option<String>,
+    pub signature_key: Option<String>,
+    pub signature_header: String,
+    pub geo_sources_header: Option<String>,
+    pub special_ip_inclusions: Option<String>,
+    pub trusted_proxies: Option<String>,
+    pub send_client_port: bool,
+    pub client_ip_headers: Option<String>,
+    pub cdn_geo_headers: bool,
+    pub upstream_proxy_protocol: Option<String>,
+    // Tables come last, TOML has no way to write plain values after them
+    pub network_overrides: Vec<NetworkOverride>,
+    pub geo_headers: HashMap<String, String>,
+    pub cdn_ranges: HashMap<String, String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(default)]
 pub struct Listener {
     pub port: u16,
+    pub metrics_port: Option<u16>,
+    pub proxy_protocol: String,
+    pub proxy_protocol_sources: Option<String>,
 }
 
 impl Default for Listener {
     fn default() -> Self {
-        Listener { port: 7479 }
+        Listener {
+            port: 7479,
+            metrics_port: None,
+            proxy_protocol: "off".to_string(),
+            proxy_protocol_sources: None,
+        }
     }
 }
 
@@ -87,13 +139,54 @@ impl Default for Settings {
                 uri: "".to_string(),
                 maxmind_id: "".to_string(),
                 maxmind_password: "".to_string(),
+                maxmind_base_url: "https://geoip.maxmind.com".to_string(),
+                maxmind_api_version: "2.1".to_string(),
+                maxmind_service: "city".to_string(),
+                maxmind_connect_timeout_ms: 1000,
+                maxmind_timeout_ms: 3000,
+                maxmind_retries: 2,
+                maxmind_retry_backoff_ms: 100,
+                circuit_breaker_failure_threshold: 5,
+                circuit_breaker_cooldown_secs: 30,
+                resolver: "maxmind".to_string(),
+                mmdb_path: None,
+                mmdb_reload_interval_secs: 60,
                 maxmind_path_inclusions: "".to_string(),
+                maxmind_country_path_inclusions: None,
+                maxmind_city_path_inclusions: None,
+                maxmind_insights_path_inclusions: None,
                 ip_path_inclusions: "".to_string(),
                 path_exclusions: None,
                 cache_capacity: 20480,
                 cache_duration_secs: 60 * 24,
+                negative_cache_duration_secs: 60 * 60,
+                transient_failure_cache_secs: 10,
                 forwarded_ip_header: None,
                 use_forwarded_ip_header_only: false,
+                geo_failure_policy: "open".to_string(),
+                geo_fail_open_path_inclusions: None,
+                geo_fail_closed_path_inclusions: None,
+                geo_failure_status: 503,
+                geo_failure_content_type: "text/plain; charset=utf-8".to_string(),
+                geo_failure_body: "Service temporarily unavailable, please try again later."
+                    .to_string(),
+                geo_locale: "en".to_string(),
+                geo_headers: HashMap::new(),
+                geo_header_format: "headers".to_string(),
+                geo_structured_header: "Prux-Geo".to_string(),
+                geo_header_encoding: "transliterate".to_string(),
+                scrub_headers: None,
+                signature_key: None,
+                signature_header: "Prux-Signature".to_string(),
+                geo_sources_header: None,
+                network_overrides: Vec::new(),
+                special_ip_inclusions: None,
+                trusted_proxies: None,
+                send_client_port: false,
+                client_ip_headers: None,
+                cdn_ranges: HashMap::new(),
+                cdn_geo_headers: false,
+                upstream_proxy_protocol: None,
             },
             listener: Default::default(),
         }
@@ -282,3 +375,41 @@ fn create_command_line_app<'help>() -> Command<'help> {
             .forbid_empty_values(false)
         )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Settings;
+    use crate::geo::overrides::NetworkOverride;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[test]
+    fn toml_round_trip() {
+        let mut settings = Settings::default();
+        let serialized = toml::to_string_pretty(&settings).unwrap();
+        let parsed = toml::from_str::<Settings>(&serialized).unwrap();
+        assert_eq!(toml::to_string_pretty(&parsed).unwrap(), serialized);
+
+        settings.server.geo_headers = HashMap::from([("isp".to_string(), "X-ISP".to_string())]);
+        settings.server.cdn_ranges =
+            HashMap::from([("cloudflare".to_string(), "cloudflare.txt".to_string())]);
+        settings.server.network_overrides = vec![NetworkOverride {
+            cidr: "10.0.0.0/8".to_string(),
+            record: serde_json::from_value(serde_json::json!({
+                "city": { "names": { "en": "Montreal" } },
+                "location": { "latitude": 45.5, "longitude": -73.58 },
+                "subdivisions": [{ "iso_code": "QC" }]
+            }))
+            .unwrap(),
+            labels: BTreeMap::from([("site".to_string(), "HQ".to_string())]),
+        }];
+        let serialized = toml::to_string_pretty(&settings).unwrap();
+        let parsed = toml::from_str::<Settings>(&serialized).unwrap();
+        assert_eq!(parsed.server.geo_headers["isp"], "X-ISP");
+        assert_eq!(parsed.server.cdn_ranges["cloudflare"], "cloudflare.txt");
+        assert_eq!(parsed.server.network_overrides[0].labels["site"], "HQ");
+        assert_eq!(
+            parsed.server.network_overrides[0].record,
+            settings.server.network_overrides[0].record
+        );
+    }
+}
@@ -0,0 +1,275 @@
+//! HMAC-SHA256 signature of the geo headers, so that upstreams reachable without prux can tell
+//! its enrichment from forged headers.
+//!
+//! The signature header is a dictionary such as
+//! `t=1700000000, h="prux-addr prux-city", sig=:base64:`, where `t` is the signing time in
+//! seconds since the Unix epoch and `h` the signed headers. The MAC covers, one per line, the
+//! timestamp, the request path and query, then `name:value` for each signed header in order.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Shortest key accepted, the output size of SHA-256
+const MIN_KEY_LEN: usize = 32;
+
+/// Reasons a signature header is rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    Missing,
+    Malformed,
+    /// The signature is older, or further in the future, than the accepted skew
+    Expired,
+    /// A signed header is missing or does not match the MAC
+    Invalid,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "signature header is missing"),
+            SignatureError::Malformed => write!(f, "signature header is malformed"),
+            SignatureError::Expired => write!(f, "signature is expired"),
+            SignatureError::Invalid => write!(f, "signature does not match"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+#[derive(Clone)]
+pub struct Signer {
+    key: Vec<u8>,
+    pub header: HeaderName,
+}
+
+impl fmt::Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signer")
+            .field("header", &self.header)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Signer {
+    pub fn new(key: &[u8], header: &str) -> Result<Self, String> {
+        if key.len() < MIN_KEY_LEN {
+            return Err(format!(
+                "Signature key must be at least {} bytes long",
+                MIN_KEY_LEN
+            ));
+        }
+
+        Ok(Signer {
+            key: key.to_vec(),
+            header: HeaderName::from_str(header.trim())
+                .map_err(|e| format!("Invalid signature header name {}: {}", header, e))?,
+        })
+    }
+
+    fn mac(
+        &self,
+        timestamp: u64,
+        path: &str,
+        headers: &[(&HeaderName, &HeaderValue)],
+    ) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b"\n");
+        mac.update(path.as_bytes());
+        mac.update(b"\n");
+        for (name, value) in headers {
+            mac.update(name.as_str().as_bytes());
+            mac.update(b":");
+            mac.update(value.as_bytes());
+            mac.update(b"\n");
+        }
+        mac
+    }
+
+    /// Signature header value covering the `signed` headers of `headers` for a request to `path`
+    pub fn sign(
+        &self,
+        headers: &HeaderMap,
+        signed: &[HeaderName],
+        path: &str,
+        timestamp: u64,
+    ) -> HeaderValue {
+        let signed = signed
+            .iter()
+            .filter_map(|name| headers.get(name).map(|value| (name, value)))
+            .collect::<Vec<_>>();
+        let mac = self.mac(timestamp, path, &signed).finalize().into_bytes();
+        let names = signed
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        HeaderValue::from_str(&format!(
+            "t={}, h=\"{}\", sig=:{}:",
+            timestamp,
+            names,
+            base64::encode(mac)
+        ))
+        .expect("Signature is always a valid header value")
+    }
+
+    /// Checks the signature of a request to `path` received at `now`, accepting `max_skew` of
+    /// clock difference. Returns the signed headers, the only ones the upstream should trust.
+    pub fn verify(
+        &self,
+        headers: &HeaderMap,
+        path: &str,
+        now: u64,
+        max_skew: Duration,
+    ) -> Result<Vec<HeaderName>, SignatureError> {
+        let value = headers
+            .get(&self.header)
+            .ok_or(SignatureError::Missing)?
+            .to_str()
+            .map_err(|_| SignatureError::Malformed)?;
+
+        let (mut timestamp, mut names, mut sig) = (None, None, None);
+        for member in value.split(',') {
+            let (key, value) = member
+                .trim()
+                .split_once('=')
+                .ok_or(SignatureError::Malformed)?;
+            match key {
+                "t" => timestamp = value.parse::<u64>().ok(),
+                "h" => names = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')),
+                "sig" => {
+                    sig = value
+                        .strip_prefix(':')
+                        .and_then(|v| v.strip_suffix(':'))
+                        .and_then(|v| base64::decode(v).ok())
+                }
+                _ => {}
+            }
+        }
+        let (timestamp, names, sig) = match (timestamp, names, sig) {
+            (Some(timestamp), Some(names), Some(sig)) => (timestamp, names, sig),
+            _ => return Err(SignatureError::Malformed),
+        };
+
+        if timestamp.abs_diff(now) > max_skew.as_secs() {
+            return Err(SignatureError::Expired);
+        }
+
+        let names = names
+            .split_whitespace()
+            .map(HeaderName::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| SignatureError::Malformed)?;
+        let signed = names
+            .iter()
+            .map(|name| {
+                headers
+                    .get(name)
+                    .map(|value| (name, value))
+                    .ok_or(SignatureError::Invalid)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.mac(timestamp, path, &signed)
+            .verify_slice(&sig)
+            .map_err(|_| SignatureError::Invalid)?;
+        Ok(names)
+    }
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SignatureError, Signer};
+    use hyper::header::{HeaderName, HeaderValue};
+    use hyper::HeaderMap;
+    use std::time::Duration;
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn signed_headers(signer: &Signer) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("prux-addr", HeaderValue::from_static("24.201.0.1"));
+        headers.insert("prux-country", HeaderValue::from_static("Canada"));
+        let signature = signer.sign(
+            &headers,
+            &[
+                HeaderName::from_static("prux-addr"),
+                HeaderName::from_static("prux-country"),
+            ],
+            "/login?next=/",
+            1_700_000_000,
+        );
+        headers.insert(signer.header.clone(), signature);
+        headers
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let signer = Signer::new(KEY, "Prux-Signature").unwrap();
+        let headers = signed_headers(&signer);
+        assert!(headers["prux-signature"]
+            .to_str()
+            .unwrap()
+            .starts_with("t=1700000000, h=\"prux-addr prux-country\", sig=:"));
+
+        let max_skew = Duration::from_secs(30);
+        assert_eq!(
+            signer.verify(&headers, "/login?next=/", 1_700_000_010, max_skew),
+            Ok(vec![
+                HeaderName::from_static("prux-addr"),
+                HeaderName::from_static("prux-country")
+            ])
+        );
+        assert_eq!(
+            signer.verify(&headers, "/admin", 1_700_000_010, max_skew),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            signer.verify(&headers, "/login?next=/", 1_700_000_100, max_skew),
+            Err(SignatureError::Expired)
+        );
+        assert_eq!(
+            signer.verify(&HeaderMap::new(), "/", 1_700_000_000, max_skew),
+            Err(SignatureError::Missing)
+        );
+    }
+
+    #[test]
+    fn forged_headers_rejected() {
+        let signer = Signer::new(KEY, "Prux-Signature").unwrap();
+        let max_skew = Duration::from_secs(30);
+
+        let mut headers = signed_headers(&signer);
+        headers.insert("prux-country", HeaderValue::from_static("France"));
+        assert_eq!(
+            signer.verify(&headers, "/login?next=/", 1_700_000_000, max_skew),
+            Err(SignatureError::Invalid)
+        );
+
+        let other = Signer::new(b"fedcba9876543210fedcba9876543210", "Prux-Signature").unwrap();
+        let headers = signed_headers(&other);
+        assert_eq!(
+            signer.verify(&headers, "/login?next=/", 1_700_000_000, max_skew),
+            Err(SignatureError::Invalid)
+        );
+
+        assert!(Signer::new(b"short", "Prux-Signature").is_err());
+    }
+}
@@ -65,7 +65,7 @@ impl UriPathMatcher {
         true
     }
 
-    pub fn iter(&self) -> Iter<UriPathSegmentMatcher> {
+    pub fn iter(&self) -> Iter<'_, UriPathSegmentMatcher> {
         self.inner.iter()
     }
 
@@ -173,7 +173,7 @@ pub trait ToRegex {
     fn as_str(&self) -> &str;
 }
 
-impl<'a> ToRegex for &'a str {
+impl ToRegex for &str {
     fn to_regex(&self) -> Result<::regex::Regex, ::regex::Error> {
         ::regex::Regex::new(self)
     }